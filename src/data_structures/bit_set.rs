@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct BitSet
 {
     bits: Vec<usize>,
@@ -58,13 +59,28 @@ impl BitSet
         self.bits.clear();
     }
 
+    // The number of bits that fit without growing, not the number of set bits.
     pub fn len(&self) -> usize
     {
         self.bits.len() * 64
     }
 
+    // Whether no bit is set.
+    pub fn is_empty(&self) -> bool
+    {
+        self.bits.iter().all(|&bits| bits == 0)
+    }
+
     pub fn data(&self) -> &[usize]
     {
         &self.bits
     }
-}
\ No newline at end of file
+}
+
+impl Default for BitSet
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
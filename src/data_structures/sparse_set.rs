@@ -1,4 +1,6 @@
-use std::{ptr::NonNull};
+use std::alloc::Layout;
+
+use crate::ecs::change_detection::{ComponentTicks, Tick};
 
 use super::type_erased_vec::TypeErasedVec;
 
@@ -20,10 +22,43 @@ impl SpraseDenseValueIndex
     }
 }
 
+#[derive(Clone)]
+pub struct SparseSetIndices<'a, const PAGE_SIZE: usize>
+{
+    dense_indices: std::slice::Iter<'a, SpraseDenseValueIndex>,
+}
+
+impl<const PAGE_SIZE: usize> Iterator for SparseSetIndices<'_, PAGE_SIZE>
+{
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        self.dense_indices.next().map(|index| index.sparse_page * PAGE_SIZE + index.sparse_index)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        self.dense_indices.size_hint()
+    }
+}
+
+impl<'a, const PAGE_SIZE: usize> SparseSetIndices<'a, PAGE_SIZE>
+{
+    // Splits the remaining indices into runs of at most `chunk_size`.
+    pub fn chunks(self, chunk_size: usize) -> impl Iterator<Item = SparseSetIndices<'a, PAGE_SIZE>>
+    {
+        self.dense_indices.as_slice().chunks(chunk_size).map(|dense_indices| SparseSetIndices { dense_indices: dense_indices.iter() })
+    }
+}
+
+// Zero-sized values, e.g. tag components, take no memory in `dense`, so a tag only costs its
+// membership and ticks.
 pub struct SparseSet<const PAGE_SIZE: usize>
 {
     dense_indecies: TypeErasedVec,
     dense: TypeErasedVec,
+    dense_ticks: TypeErasedVec,
     sparse: Vec<[usize; PAGE_SIZE]>, // Use a vector of options instead of a hashmap
 }
 
@@ -31,21 +66,30 @@ impl<const PAGE_SIZE: usize> SparseSet<PAGE_SIZE>
 {
     pub fn new<T>() -> Self
     {
-        let mut dense = TypeErasedVec::new::<T>();
+        Self::from_dense(TypeErasedVec::new::<T>())
+    }
+
+    pub fn from_layout(layout: Layout, drop_fn: Option<unsafe fn(*mut u8)>) -> Self
+    {
+        Self::from_dense(TypeErasedVec::from_layout(layout, drop_fn))
+    }
+
+    fn from_dense(mut dense: TypeErasedVec) -> Self
+    {
         let mut dense_indecies = TypeErasedVec::new::<SpraseDenseValueIndex>();
+        let mut dense_ticks = TypeErasedVec::new::<ComponentTicks>();
         let mut sparse: Vec<[usize; PAGE_SIZE]> = Vec::new();
 
         dense.reserve(PAGE_SIZE);
         dense_indecies.reserve(PAGE_SIZE);
+        dense_ticks.reserve(PAGE_SIZE);
         sparse.reserve(PAGE_SIZE);
 
-        dense.emplace();
-        dense_indecies.emplace();
-
         Self
         {
             dense_indecies,
             dense,
+            dense_ticks,
             sparse
         }
     }
@@ -57,98 +101,134 @@ impl<const PAGE_SIZE: usize> SparseSet<PAGE_SIZE>
         (page, index)
     }
 
-    pub fn emplace(&mut self, index: usize) -> bool
+    // The sparse pages store `dense index + 1`, so that 0 can mark an empty slot.
+    fn dense_index(&self, index: usize) -> Option<usize>
     {
         let (page, index) = Self::map_index(index);
+        self.sparse.get(page).and_then(|page_sparse| page_sparse[index].checked_sub(1))
+    }
+
+    pub fn reserve(&mut self, additional: usize)
+    {
+        self.dense.reserve_exact(additional);
+        self.dense_indecies.reserve_exact(additional);
+        self.dense_ticks.reserve_exact(additional);
+    }
+
+    pub fn reserve_index(&mut self, index: usize)
+    {
+        let (page, _) = Self::map_index(index);
 
         if page >= self.sparse.len() {
             self.sparse.resize(page + 1, [0; PAGE_SIZE]);
         }
+    }
+
+    pub fn contains(&self, index: usize) -> bool
+    {
+        self.dense_index(index).is_some()
+    }
+
+    // Inserts or overwrites the value at `index`, stamping it as added and/or changed at `tick`.
+    pub fn set<T>(&mut self, index: usize, value: T, tick: Tick)
+    {
+        debug_assert_eq!(Layout::new::<T>(), self.layout());
+        let value = std::mem::ManuallyDrop::new(value);
+        unsafe { self.set_raw(index, &*value as *const T as *const u8, tick) };
+    }
 
-        let page_sparse = &mut self.sparse[page];
-    
-        if page_sparse[index] != 0
+    // Like `set`, but moves the value out of `value`, which must point to an item of this set's layout.
+    pub unsafe fn set_raw(&mut self, index: usize, value: *const u8, tick: Tick)
+    {
+        if let Some(dense_index) = self.dense_index(index)
         {
-            return false;
+            self.dense.replace_raw(dense_index, value);
+            self.dense_ticks.get_typed_mut::<ComponentTicks>(dense_index).changed = tick;
+            return;
         }
-        
-        self.dense.emplace();
-        self.dense_indecies.emplace();
-        //self.dense_indecies.push(SpraseDenseValueIndex::new(page, index));
 
-        let dense_index_value = self.dense_indecies.get_typed_mut::<SpraseDenseValueIndex>(self.dense_indecies.len() - 1);
-        dense_index_value.sparse_index = index;
-        dense_index_value.sparse_page = page;
+        self.reserve_index(index);
 
-        let dense_index = self.dense.len() - 1;
+        let (page, index) = Self::map_index(index);
 
-        page_sparse[index] = dense_index;
+        self.dense.push_raw(value);
+        self.dense_indecies.push(SpraseDenseValueIndex::new(page, index));
+        self.dense_ticks.push(ComponentTicks::new(tick));
 
-        true
+        self.sparse[page][index] = self.dense.len();
     }
 
-    pub fn set<T>(&mut self, index: usize, value: T)
+    pub fn get<T>(&self, index: usize) -> Option<&T>
     {
-        if self.emplace(index)
-        {
-            let dense_index = self.dense.len() - 1;
-            *self.dense.get_typed_mut::<T>(dense_index) = value;
-        }
+        self.dense_index(index).map(|dense_index| self.dense.get_typed::<T>(dense_index))
     }
 
-    pub fn get<T>(&self, index: usize) -> Option<&T>
+    pub fn get_mut<T>(&mut self, index: usize) -> Option<&mut T>
     {
-        let (page, index) = Self::map_index(index);
-        self.sparse.get(page).and_then(|page_sparse| 
-        {
-            let dense_index = page_sparse[index];
-            if dense_index != 0
-            {
-                Some(self.dense.get_typed::<T>(dense_index))
-            }
-            else
-            {
-                None
-            }
-        })
+        self.dense_index(index).map(|dense_index| self.dense.get_typed_mut::<T>(dense_index))
+    }
+
+    pub fn get_ptr<T>(&self, index: usize) -> Option<*mut T>
+    {
+        self.dense_index(index).map(|dense_index| self.dense.get_ptr(dense_index) as *mut T)
+    }
+
+    pub fn get_raw_ptr(&self, index: usize) -> Option<*mut u8>
+    {
+        self.dense_index(index).map(|dense_index| self.dense.get_ptr(dense_index))
+    }
+
+    pub fn get_ticks(&self, index: usize) -> Option<&ComponentTicks>
+    {
+        self.dense_index(index).map(|dense_index| self.dense_ticks.get_typed::<ComponentTicks>(dense_index))
+    }
+
+    pub fn get_with_ticks_ptr<T>(&self, index: usize) -> Option<(*mut T, *mut ComponentTicks)>
+    {
+        self.dense_index(index).map(|dense_index| (self.dense.get_ptr(dense_index) as *mut T, self.dense_ticks.get_ptr(dense_index) as *mut ComponentTicks))
     }
 
-    pub fn remove(&mut self, index: usize)
+    // Returns whether a value was stored at `index`.
+    pub fn remove(&mut self, index: usize) -> bool
     {
+        let dense_index = match self.dense_index(index) {
+            Some(dense_index) => dense_index,
+            None => return false,
+        };
+
         let (page, index) = Self::map_index(index);
+        let last_dense_index = self.dense.len() - 1;
 
-        if let Some(page_sparse) = self.sparse.get_mut(page)
+        self.sparse[page][index] = 0;
+
+        if dense_index != last_dense_index
         {
-            let dense_index = page_sparse[index];
-            let last_dense_index = self.dense.len() - 1;
-            if dense_index != 0
-            {
-                let last_dense_value_index = self.dense_indecies.get_typed::<SpraseDenseValueIndex>(last_dense_index);
-                let last_page = last_dense_value_index.sparse_page;
-                let last_index = last_dense_value_index.sparse_index;
-
-                page_sparse[index] = 0;
-
-                if dense_index != last_dense_index
-                {
-                    let last_page_sparse = self.sparse.get_mut(last_page).unwrap();
-                    last_page_sparse[last_index] = dense_index;
-                    self.dense.remove_swap_with_last(dense_index);
-                    self.dense_indecies.remove_swap_with_last(dense_index);
-                }
-                else
-                {
-                    self.dense.remove_swap_with_last(dense_index);
-                    self.dense_indecies.remove_swap_with_last(dense_index);
-                }
-            }
+            let last_dense_value_index = self.dense_indecies.get_typed::<SpraseDenseValueIndex>(last_dense_index);
+            let last_page = last_dense_value_index.sparse_page;
+            let last_index = last_dense_value_index.sparse_index;
+
+            self.sparse[last_page][last_index] = dense_index + 1;
+        }
+
+        self.dense.remove_swap_with_last(dense_index);
+        self.dense_indecies.remove_swap_with_last(dense_index);
+        self.dense_ticks.remove_swap_with_last(dense_index);
+
+        true
+    }
+
+    pub fn indices(&self) -> SparseSetIndices<'_, PAGE_SIZE>
+    {
+        SparseSetIndices
+        {
+            dense_indices: self.dense_indecies.iter_typed::<SpraseDenseValueIndex>(),
         }
     }
 
     pub fn iter<T: 'static>(&self) -> impl Iterator<Item = (usize, &T)>
     {
-        let dense_indices = self.dense_indecies.iter_typed::<SpraseDenseValueIndex>().skip(1);
-        let dense_values = self.dense.iter_typed::<T>().skip(1);
+        let dense_indices = self.dense_indecies.iter_typed::<SpraseDenseValueIndex>();
+        let dense_values = self.dense.iter_typed::<T>();
 
         dense_indices.zip(dense_values).map(|(index, value)| {
             let idx = index.sparse_page * PAGE_SIZE + index.sparse_index;
@@ -158,8 +238,8 @@ impl<const PAGE_SIZE: usize> SparseSet<PAGE_SIZE>
 
     pub fn iter_mut<T: 'static>(&mut self) -> impl Iterator<Item = (usize, &mut T)>
     {
-        let dense_indices = self.dense_indecies.iter_typed::<SpraseDenseValueIndex>().skip(1);
-        let dense_values = self.dense.iter_typed_mut::<T>().skip(1);
+        let dense_indices = self.dense_indecies.iter_typed::<SpraseDenseValueIndex>();
+        let dense_values = self.dense.iter_typed_mut::<T>();
 
         dense_indices.zip(dense_values).map(|(index, value)| {
             let idx = index.sparse_page * PAGE_SIZE + index.sparse_index;
@@ -169,6 +249,45 @@ impl<const PAGE_SIZE: usize> SparseSet<PAGE_SIZE>
 
     pub fn len(&self) -> usize
     {
-        self.dense.len() - 1
+        self.dense.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    pub fn layout(&self) -> Layout
+    {
+        self.dense.layout()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::SparseSet;
+
+    struct Tag;
+
+    #[test]
+    fn zero_sized_values_keep_membership_and_ticks()
+    {
+        let mut set = SparseSet::<4>::new::<Tag>();
+        set.set(2, Tag, 1);
+        set.set(9, Tag, 2);
+        set.set(2, Tag, 3);
+
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(9) && !set.contains(3));
+        assert_eq!(set.get_ticks(2).map(|ticks| (ticks.added, ticks.changed)), Some((1, 3)));
+        assert_eq!(set.indices().collect::<Vec<_>>(), [2, 9]);
+        assert_eq!(set.iter::<Tag>().map(|(index, _)| index).collect::<Vec<_>>(), [2, 9]);
+        assert_eq!(set.iter_mut::<Tag>().count(), 2);
+
+        assert!(set.remove(2));
+        assert!(!set.remove(2));
+        assert_eq!(set.indices().collect::<Vec<_>>(), [9]);
+        assert_eq!(set.get_ticks(9).map(|ticks| ticks.added), Some(2));
+    }
+}
@@ -1,34 +1,60 @@
-use std::{alloc::GlobalAlloc, ops::Index, ptr::NonNull};
+use std::{alloc::GlobalAlloc, ptr::NonNull};
 
 pub struct TypeErasedVec {
     data: NonNull<u8>,
     layout: std::alloc::Layout,
-    bytes: usize, // in bytes
+    drop_fn: Option<unsafe fn(*mut u8)>,
+    len: usize, // in items, so zero-sized items do not need a division by their size
     capacity: usize, // in items -> total bytes = layout.size() * capacity
 }
 
+pub unsafe fn drop_erased<T>(ptr: *mut u8) {
+    std::ptr::drop_in_place(ptr as *mut T);
+}
+
 impl TypeErasedVec {
     pub fn new<T>() -> Self {
-        let layout = std::alloc::Layout::new::<T>();
-        let data = unsafe { std::alloc::System.alloc(layout) };
-        let data = NonNull::new(data).expect("Allocation failed");
+        let drop_fn = if std::mem::needs_drop::<T>() { Some(drop_erased::<T> as unsafe fn(*mut u8)) } else { None };
+        Self::from_layout(std::alloc::Layout::new::<T>(), drop_fn)
+    }
 
+    // For items without a Rust type; `drop_fn` is called with a pointer to each item that is dropped.
+    pub fn from_layout(layout: std::alloc::Layout, drop_fn: Option<unsafe fn(*mut u8)>) -> Self {
         Self {
-            data,
+            data: NonNull::new(std::ptr::without_provenance_mut(layout.align())).unwrap(),
             layout,
-            bytes: 0,
-            capacity: 0,
+            drop_fn,
+            len: 0,
+            // Zero-sized items never need an allocation.
+            capacity: if layout.size() == 0 { usize::MAX } else { 0 },
+        }
+    }
+
+    fn allocation_layout(&self, capacity: usize) -> std::alloc::Layout {
+        std::alloc::Layout::from_size_align(self.layout.size() * capacity, self.layout.align()).unwrap()
+    }
+
+    // Frees the buffer without touching the items in it.
+    unsafe fn dealloc(&mut self) {
+        if self.capacity > 0 && self.layout.size() > 0 {
+            std::alloc::System.dealloc(self.data.as_ptr(), self.allocation_layout(self.capacity));
         }
     }
 
     pub fn set_capacity(&mut self, new_capacity: usize) {
-        let new_layout = std::alloc::Layout::from_size_align(self.layout.size() * new_capacity, self.layout.align()).unwrap();
+        assert!(new_capacity >= self.len());
+
+        if self.layout.size() == 0 {
+            return;
+        }
+
+        let new_layout = self.allocation_layout(new_capacity);
         let new_data = unsafe { std::alloc::System.alloc(new_layout) };
         let new_data = NonNull::new(new_data).expect("Allocation failed");
 
         unsafe {
-            std::ptr::copy_nonoverlapping(self.data.as_ptr(), new_data.as_ptr(), self.bytes);
-            std::alloc::System.dealloc(self.data.as_ptr(), self.layout);
+            std::ptr::copy_nonoverlapping(self.data.as_ptr(), new_data.as_ptr(), self.bytes());
+            self.dealloc();
         }
 
         self.data = new_data;
@@ -36,38 +62,53 @@ impl TypeErasedVec {
     }
 
     pub fn reserve(&mut self, additional: usize) {
-        if self.len() + additional > self.capacity {
-            let new_capacity = self.capacity + additional;
-            self.set_capacity(new_capacity);    
+        let required_capacity = self.len() + additional;
+        if required_capacity > self.capacity {
+            self.set_capacity(required_capacity.max(self.capacity * 2));
+        }
+    }
+
+    pub fn reserve_exact(&mut self, additional: usize) {
+        let required_capacity = self.len() + additional;
+        if required_capacity > self.capacity {
+            self.set_capacity(required_capacity);
         }
     }
 
     pub fn reserve_typed<T>(&mut self, additional: usize) {
         let layout = std::alloc::Layout::new::<T>();
-        let additional = (additional * layout.size() / self.layout.size()).max(1);
+        let additional = (additional * layout.size() / self.layout.size().max(1)).max(1);
         self.reserve(additional);
     }
 
-    pub fn emplace(&mut self) {
+    pub fn push<T>(&mut self, value: T) {
+        debug_assert_eq!(std::alloc::Layout::new::<T>(), self.layout);
         self.reserve(1);
         unsafe {
-            let _ = self.data.as_ptr().add(self.bytes);
+            std::ptr::write(self.data.as_ptr().add(self.bytes()) as *mut T, value);
         }
-        self.bytes += self.layout.size();
+        self.len += 1;
     }
 
-    pub fn emplace_typed<T>(&mut self) -> &mut T {
-        let layout = std::alloc::Layout::new::<T>();
-        self.reserve_typed::<T>(1);
-        let offset = self.bytes;
-        self.bytes += layout.size();
-        unsafe {
-            &mut *(self.data.as_ptr().add(offset) as *mut T)
-        }
+    // Moves `layout.size()` bytes from `value` into the vec; the caller must not drop the source afterwards.
+    pub unsafe fn push_raw(&mut self, value: *const u8) {
+        self.reserve(1);
+        std::ptr::copy_nonoverlapping(value, self.data.as_ptr().add(self.bytes()), self.layout.size());
+        self.len += 1;
     }
 
-    pub fn push<T>(&mut self, value: T) {
-        *self.emplace_typed::<T>() = value;
+    // Drops the item at `index` and moves `value` into its place.
+    pub unsafe fn replace_raw(&mut self, index: usize, value: *const u8) {
+        assert!(index < self.len());
+        self.drop_in_place(index);
+        std::ptr::copy_nonoverlapping(value, self.data.as_ptr().add(index * self.layout.size()), self.layout.size());
+    }
+
+    // The buffer lives behind a raw pointer, so writing through the returned pointer does not
+    // conflict with the shared borrow of `self`. Callers are responsible for exclusive access.
+    pub fn get_ptr(&self, index: usize) -> *mut u8 {
+        assert!(index < self.len());
+        unsafe { self.data.as_ptr().add(index * self.layout.size()) }
     }
 
     pub fn get_typed<T>(&self, index: usize) -> &T {
@@ -84,26 +125,49 @@ impl TypeErasedVec {
 
     pub fn remove_swap_with_last(&mut self, index: usize) {
         assert!(index < self.len());
-        self.bytes -= self.layout.size();
+        self.drop_in_place(index);
+        self.len -= 1;
         if index < self.len() {
             unsafe {
-                let last_ptr = self.as_ptr().add(self.bytes);
+                let last_ptr = self.as_ptr().add(self.bytes());
                 let ptr = self.as_mut_ptr().add(index * self.layout.size());
                 std::ptr::copy_nonoverlapping(last_ptr, ptr, self.layout.size());
             }
         }
     }
 
+    // Like `remove_swap_with_last`, but without dropping the item, which the caller has moved out.
+    pub unsafe fn forget_swap_with_last(&mut self, index: usize) {
+        assert!(index < self.len());
+        self.len -= 1;
+        if index < self.len() {
+            let last_ptr = self.as_ptr().add(self.bytes());
+            let ptr = self.as_mut_ptr().add(index * self.layout.size());
+            std::ptr::copy_nonoverlapping(last_ptr, ptr, self.layout.size());
+        }
+    }
+
     pub fn clear(&mut self) {
-        self.bytes = 0;
+        // Shrink first, so a panicking drop can at worst leak the remaining items.
+        let len = self.len();
+        self.len = 0;
+        for index in 0..len {
+            self.drop_in_place(index);
+        }
+    }
+
+    fn drop_in_place(&mut self, index: usize) {
+        if let Some(drop_fn) = self.drop_fn {
+            unsafe { drop_fn(self.data.as_ptr().add(index * self.layout.size())) };
+        }
     }
 
     pub fn as_slice(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.bytes) }
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.bytes()) }
     }
 
     pub fn as_slice_mut(&mut self) -> &mut [u8] {
-        unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr(), self.bytes) }
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr(), self.bytes()) }
     }
 
     pub fn as_typed_slice<T>(&self) -> &[T] {
@@ -111,8 +175,7 @@ impl TypeErasedVec {
     }
 
     pub fn as_typed_slice_mut<T>(&mut self) -> &mut [T] {
-        let layout = std::alloc::Layout::new::<T>();
-        unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr() as *mut T, self.bytes / layout.size()) }
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr() as *mut T, self.len) }
     }
 
     pub fn as_ptr(&self) -> *const u8 {
@@ -131,7 +194,8 @@ impl TypeErasedVec {
         self.data.as_ptr() as *mut T
     }
 
-    pub fn len     (&self) -> usize              { self.bytes / self.layout.size() }
+    pub fn len     (&self) -> usize              { self.len }
+    pub fn bytes   (&self) -> usize              { self.len * self.layout.size() }
     pub fn is_empty(&self) -> bool               { self.len() == 0 }
     pub fn capacity(&self) -> usize              { self.capacity }
     pub fn layout  (&self) -> std::alloc::Layout { self.layout   }
@@ -155,8 +219,65 @@ impl TypeErasedVec {
 
 impl Drop for TypeErasedVec {
     fn drop(&mut self) {
-        unsafe {
-            std::alloc::System.dealloc(self.data.as_ptr(), self.layout);
+        self.clear();
+        unsafe { self.dealloc() };
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, sync::atomic::{AtomicUsize, Ordering}};
+
+    use super::TypeErasedVec;
+
+    #[test]
+    fn items_are_dropped_exactly_once() {
+        let counter = Rc::new(());
+        let mut vec = TypeErasedVec::new::<Rc<()>>();
+        for _ in 0..3 {
+            vec.push(counter.clone());
         }
+
+        vec.remove_swap_with_last(0);
+        assert_eq!(Rc::strong_count(&counter), 3);
+        assert_eq!(vec.len(), 2);
+
+        vec.clear();
+        assert_eq!(Rc::strong_count(&counter), 1);
+
+        vec.push(counter.clone());
+        drop(vec);
+        assert_eq!(Rc::strong_count(&counter), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn zero_sized_items_are_counted_and_dropped_exactly_once() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Tag;
+
+        impl Drop for Tag {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let mut vec = TypeErasedVec::new::<Tag>();
+        for _ in 0..4 {
+            vec.push(Tag);
+        }
+        assert_eq!(vec.len(), 4);
+        assert_eq!(vec.bytes(), 0);
+        assert_eq!(vec.iter_typed::<Tag>().count(), 4);
+
+        vec.remove_swap_with_last(1);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        assert_eq!(vec.len(), 3);
+
+        vec.clear();
+        assert_eq!(DROPS.load(Ordering::SeqCst), 4);
+
+        vec.push(Tag);
+        drop(vec);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 5);
+    }
+}
@@ -1,14 +1,30 @@
-use std::{any::{Any, TypeId}, collections::HashMap, iter, ops::Deref};
+use std::{alloc::Layout, any::{type_name, Any, TypeId}, borrow::Cow, cell::{RefCell, UnsafeCell}, collections::HashMap, rc::Rc, sync::{atomic::{AtomicUsize, Ordering}, Arc, OnceLock}};
 
 pub mod component;
 pub mod system;
 pub mod entity;
 pub mod query;
+pub mod bundle;
+pub mod command;
+pub mod change_detection;
+pub mod removal_detection;
+pub mod archetype;
+pub mod event;
+pub mod schedule;
+pub mod task_pool;
 
-use component::{Component, ComponentTypeUUID, ComponentUUID};
-use entity::EntityUUID;
-use query::{ComponentQuery, ComponentQueryMut};
-use system::System;
+use archetype::{Archetypes, EntityLocation, EMPTY_ARCHETYPE};
+use bundle::Bundle;
+use change_detection::{ComponentTicks, Mut, SystemTicks, Tick};
+use command::Commands;
+use component::{registry::{ComponentInfo, ComponentRegistry, DropFn, RegistryError, StorageKind}, Component, ComponentHooks, ComponentTypeUUID};
+use event::Events;
+use removal_detection::EntityLog;
+use entity::{Entity, EntityBuilder, EntityGeneration, EntityUUID};
+use query::{filter::QueryFilter, ComponentQuery, ComponentQueryMut, QueryIter, QueryTuple, ReadOnlyQueryTuple};
+use schedule::{ExecutorKind, FixedUpdate, Render, Schedule, ScheduleError, ScheduleLabel, Start, SystemConfig, SystemKind, Update};
+use system::{function::IntoSystem, Phase, PhaseSystem, RegisteredSystem, RunSystem, System};
+use task_pool::TaskPool;
 
 use crate::data_structures::sparse_set::SparseSet;
 use crate::data_structures::bit_set::BitSet;
@@ -16,270 +32,1442 @@ use crate::data_structures::bit_set::BitSet;
 pub struct ECSStorage
 {
     components: HashMap<ComponentTypeUUID, SparseSet<1000>>,
-    component_type_id_to_uuid: HashMap<TypeId, ComponentTypeUUID>,
+    archetypes: Archetypes,
+    entity_locations: Vec<EntityLocation>,
+    component_registry: ComponentRegistry,
+    bundle_component_types: HashMap<TypeId, Arc<[ComponentTypeUUID]>>,
     entity_components_bitset: HashMap<EntityUUID, BitSet>,
+    entity_generations: Vec<EntityGeneration>,
     deleted_entities: Vec<EntityUUID>,
-    entity_uuid_counter: EntityUUID,
-    component_uuid_counter: ComponentUUID,
+    entity_uuid_counter: Arc<AtomicUsize>,
+    commands: Commands,
+    // Behind an `UnsafeCell` so `ResMut` system parameters can borrow a resource mutably from a
+    // shared storage; the system's access set guarantees nothing else borrows it meanwhile.
+    resources: HashMap<TypeId, UnsafeCell<Box<dyn Any>>>,
+    event_updates: HashMap<TypeId, fn(&mut dyn Any)>,
+    change_tick: Tick,
+    last_change_tick: Tick,
+    removed_components: HashMap<ComponentTypeUUID, EntityLog>,
+    despawned_entities: EntityLog,
+    // Runs the batches of `QueryIter::par_for_each`, started on first use.
+    task_pool: OnceLock<TaskPool>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct ECS
 {
     storage: ECSStorage,
-    dynamic_systems: HashMap<TypeId, Box<dyn System>>,
+    // Keyed by the `TypeId` of the label.
+    schedules: HashMap<TypeId, Schedule>,
+    executor: ExecutorKind,
 }
 
 impl ECSStorage
 {
     pub fn new() -> Self
     {
+        let entity_uuid_counter = Arc::new(AtomicUsize::new(0));
+
         Self
         {
+            commands: Commands::new(entity_uuid_counter.clone()),
             components: HashMap::new(),
-            component_type_id_to_uuid: HashMap::new(),
+            archetypes: Archetypes::new(),
+            entity_locations: Vec::new(),
+            component_registry: ComponentRegistry::new(),
+            bundle_component_types: HashMap::new(),
             entity_components_bitset: HashMap::new(),
+            entity_generations: Vec::new(),
             deleted_entities: Vec::new(),
-            entity_uuid_counter: 0,
-            component_uuid_counter: 0,
+            entity_uuid_counter,
+            resources: HashMap::new(),
+            event_updates: HashMap::new(),
+            change_tick: 1,
+            last_change_tick: 0,
+            removed_components: HashMap::new(),
+            despawned_entities: EntityLog::new(),
+            task_pool: OnceLock::new(),
         }
     }
 
-    pub fn create_entity(&mut self) -> EntityUUID
+    pub fn create_entity(&mut self) -> Entity
     {
-        let uuid = if self.deleted_entities.is_empty()
+        let index = if let Some(index) = self.deleted_entities.pop()
         {
-            self.entity_uuid_counter += 1;
-            self.entity_uuid_counter
+            index
         }
         else
         {
-            self.deleted_entities.pop().unwrap()
+            self.entity_uuid_counter.fetch_add(1, Ordering::Relaxed) + 1
         };
 
-        self.entity_components_bitset.insert(uuid, BitSet::new());
+        self.place_entity(index);
+
+        Entity::new(index, self.entity_generations[index])
+    }
+
+    // Brings an entity reserved by `Commands::spawn_empty` to life.
+    fn spawn_reserved(&mut self, entity: Entity)
+    {
+        self.place_entity(entity.index());
+    }
+
+    fn place_entity(&mut self, index: EntityUUID)
+    {
+        if index >= self.entity_generations.len()
+        {
+            self.entity_generations.resize(index + 1, 0);
+            self.entity_locations.resize(index + 1, EntityLocation::default());
+        }
+
+        self.entity_components_bitset.insert(index, BitSet::new());
+        self.entity_locations[index] = self.archetypes.push_entity(EMPTY_ARCHETYPE, index);
+    }
 
-        uuid
+    // Points the entity that was swapped into a vacated archetype row at its new row.
+    fn relocate(&mut self, moved: Option<EntityUUID>, location: EntityLocation)
+    {
+        if let Some(moved) = moved
+        {
+            self.entity_locations[moved] = location;
+        }
     }
 
-    pub fn remove_entity(&mut self, uuid: EntityUUID) -> bool
+    pub fn remove_entity(&mut self, entity: Entity) -> bool
     {
-        if let Some(bit_set) = self.entity_components_bitset.remove(&uuid) {
-            let mut component_type_uuid = 0;
-            for bits in bit_set.data() {
-                let mut bit = 0;
-                while bit < usize::BITS as usize {
-                    if bits & (1 << bit) != 0 {
-                        let component_type_id = self.component_type_id_to_uuid.iter().find(|(_, &uuid)| uuid == component_type_uuid).unwrap().0;
-                        let component_type_uuid = self.component_type_id_to_uuid.get(component_type_id).unwrap();
-                        let components = self.components.get_mut(component_type_uuid).unwrap();
-                        components.remove(uuid);
+        if !self.has_entity(entity)
+        {
+            return false;
+        }
+
+        let index = entity.index();
+        let bit_set = self.entity_components_bitset.remove(&index).unwrap();
+
+        for (word_index, bits) in bit_set.data().iter().enumerate()
+        {
+            for bit in 0..usize::BITS as usize
+            {
+                if bits & (1 << bit) != 0
+                {
+                    let component_type_uuid = word_index * usize::BITS as usize + bit;
+                    if let Some(components) = self.components.get_mut(&component_type_uuid)
+                    {
+                        components.remove(index);
                     }
-                    bit += 1;
-                    component_type_uuid += 1;
+
+                    self.on_component_removed(component_type_uuid, entity);
                 }
             }
-            self.deleted_entities.push(uuid);
-            true
-        } else {
-            false
         }
+
+        let location = self.entity_locations[index];
+        let moved = self.archetypes.remove_entity(location);
+        self.relocate(moved, location);
+
+        self.despawned_entities.send(entity);
+
+        self.entity_generations[index] = self.entity_generations[index].wrapping_add(1);
+        self.deleted_entities.push(index);
+
+        true
+    }
+
+    pub fn has_entity(&self, entity: Entity) -> bool
+    {
+        self.entity_components_bitset.contains_key(&entity.index())
+            && self.entity_generations.get(entity.index()) == Some(&entity.generation())
+    }
+
+    pub fn entities_count(&self) -> usize
+    {
+        self.entity_components_bitset.len()
+    }
+
+    fn register_component_type<T>(&mut self) -> ComponentTypeUUID where T: 'static
+    {
+        let component_type_uuid = self.component_registry.register::<T>();
+
+        if self.component_registry.info(component_type_uuid).unwrap().storage_kind() == StorageKind::SparseSet
+        {
+            self.components.entry(component_type_uuid).or_insert_with(|| SparseSet::<1000>::new::<T>());
+        }
+        // Readers can register with the log before the first removal.
+        self.removed_components.entry(component_type_uuid).or_default();
+
+        component_type_uuid
     }
 
-    pub fn has_entity(&self, uuid: EntityUUID) -> bool
+    fn storage_kind(&self, component_type_uuid: ComponentTypeUUID) -> StorageKind
     {
-        self.entity_components_bitset.contains_key(&uuid)
+        self.component_registry.info(component_type_uuid).unwrap().storage_kind()
     }
 
-    pub fn add_component<T>(&mut self, uuid: EntityUUID) where T: 'static
+    // Table components are packed per archetype, which makes iterating many of them together fast,
+    // while sparse set components are cheaper to add and remove. Panics if any entity has the component.
+    pub fn set_storage_kind(&mut self, component_type_uuid: ComponentTypeUUID, storage_kind: StorageKind)
     {
-        let component_type_id = TypeId::of::<T>();
-        let component_type_uuid = *self.component_type_id_to_uuid.entry(component_type_id).or_insert_with(|| 
+        let info = self.component_registry.info_mut(component_type_uuid).expect("Component type is not registered");
+        if info.storage_kind() == storage_kind
         {
-            self.component_uuid_counter += 1;
-            self.component_uuid_counter
-        });
+            return;
+        }
 
-        let components = self.components.entry(component_type_uuid).or_insert_with(|| SparseSet::<1000>::new::<T>());
-        
-        components.emplace(uuid);
+        let in_use = match info.storage_kind() {
+            StorageKind::SparseSet => self.components.get(&component_type_uuid).is_some_and(|components| !components.is_empty()),
+            StorageKind::Table => self.archetypes.iter().any(|archetype| archetype.contains(component_type_uuid) && !archetype.is_empty()),
+        };
+        assert!(!in_use, "Cannot change the storage of component {} while entities have it", info.name());
+
+        info.set_storage_kind(storage_kind);
 
-        if let Some(bitset) = self.entity_components_bitset.get_mut(&uuid) {
-            bitset.set(component_type_uuid);
+        match storage_kind {
+            StorageKind::SparseSet => { self.components.insert(component_type_uuid, SparseSet::<1000>::from_layout(info.layout(), info.drop_fn())); }
+            StorageKind::Table => { self.components.remove(&component_type_uuid); }
         }
     }
 
-    pub fn remove_component<T>(&mut self, uuid: EntityUUID) where T: 'static
+    pub fn register_component<T>(&mut self) -> &mut ComponentInfo where T: 'static
+    {
+        let component_type_uuid = self.register_component_type::<T>();
+        self.component_registry.info_mut(component_type_uuid).unwrap()
+    }
+
+    // Registers a component type that only exists at runtime; its values are accessed through
+    // `insert_raw`, `get_raw` and `get_raw_mut`.
+    pub fn register_dynamic_component(&mut self, name: impl Into<Cow<'static, str>>, layout: Layout, drop_fn: Option<DropFn>) -> Result<ComponentTypeUUID, RegistryError>
+    {
+        let component_type_uuid = self.component_registry.register_dynamic(name.into(), layout, drop_fn)?;
+        self.components.insert(component_type_uuid, SparseSet::<1000>::from_layout(layout, drop_fn));
+        self.removed_components.entry(component_type_uuid).or_default();
+        Ok(component_type_uuid)
+    }
+
+    pub fn component_registry(&self) -> &ComponentRegistry
     {
-        let component_type_id = TypeId::of::<T>();
-        let component_type_uuid = *self.component_type_id_to_uuid.get(&component_type_id).unwrap();
+        &self.component_registry
+    }
+
+    pub fn add_component<T>(&mut self, entity: Entity) where T: Component
+    {
+        self.insert_component(entity, T::new());
+    }
 
-        if let Some(components) = self.components.get_mut(&component_type_uuid)
+    pub fn component_type_uuid<T>(&self) -> Option<ComponentTypeUUID> where T: 'static
+    {
+        self.component_registry.id::<T>()
+    }
+
+    fn bundle_component_types<B>(&mut self) -> Arc<[ComponentTypeUUID]> where B: Bundle
+    {
+        let bundle_type_id = TypeId::of::<B>();
+        if let Some(component_type_uuids) = self.bundle_component_types.get(&bundle_type_id)
         {
-            components.remove(uuid);
+            return component_type_uuids.clone();
         }
+
+        let mut component_type_uuids = Vec::new();
+        B::register_components(self, &mut component_type_uuids);
+
+        let mut unique = component_type_uuids.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert!(unique.len() == component_type_uuids.len(), "Bundle {} contains the same component type more than once", type_name::<B>());
+
+        let component_type_uuids: Arc<[ComponentTypeUUID]> = component_type_uuids.into();
+        self.bundle_component_types.insert(bundle_type_id, component_type_uuids.clone());
+        component_type_uuids
     }
 
-    pub fn get_component<T>(&self, uuid: EntityUUID) -> Option<&T> where T: 'static
+    // Moves every value into the storage, moving the entity to a new archetype first if it gains
+    // table components. Hooks run after each write.
+    // Safety: the entity must be alive, the ids registered and unique, and every pointer must point
+    // to a valid value of its component's layout, which the caller gives up.
+    unsafe fn write_components_raw(&mut self, entity: Entity, components: &[(ComponentTypeUUID, *mut u8)])
     {
-        let component_type_id = TypeId::of::<T>();
-        let component_type_uuid = match self.component_type_id_to_uuid.get(&component_type_id) {
-            Some(&uuid) => uuid,
-            None => return None,
-        };
+        let index = entity.index();
+        let mut location = self.entity_locations[index];
+        let source = location.archetype;
 
-        if let Some(components) = self.components.get(&component_type_uuid)
+        let mut target = source;
+        for &(component_type_uuid, _) in components
         {
-            components.get::<T>(uuid)
+            if self.storage_kind(component_type_uuid) == StorageKind::Table
+            {
+                target = self.archetypes.with_component(target, component_type_uuid, &self.component_registry);
+            }
         }
-        else
+
+        if target != source
+        {
+            let (new_location, moved) = self.archetypes.move_entity(location, target);
+            self.relocate(moved, location);
+            self.entity_locations[index] = new_location;
+            location = new_location;
+        }
+
+        for &(component_type_uuid, value) in components
         {
-            None
+            let added = match self.storage_kind(component_type_uuid) {
+                StorageKind::SparseSet =>
+                {
+                    let components = self.components.get_mut(&component_type_uuid).unwrap();
+                    let added = !components.contains(index);
+                    components.set_raw(index, value, self.change_tick);
+                    added
+                }
+                StorageKind::Table =>
+                {
+                    self.archetypes.write(location, component_type_uuid, value, self.change_tick);
+                    !self.archetypes.get(source).contains(component_type_uuid)
+                }
+            };
+
+            self.on_component_written(component_type_uuid, entity, added);
+        }
+
+        if let Some(bitset) = self.entity_components_bitset.get_mut(&index) {
+            for &(component_type_uuid, _) in components
+            {
+                bitset.set(component_type_uuid);
+            }
         }
     }
-    pub fn iter_components<T: 'static>(&self) -> Box<dyn Iterator<Item = (usize, &T)> + '_>
+
+    fn on_component_written(&mut self, component_type_uuid: ComponentTypeUUID, entity: Entity, added: bool)
     {
-        let component_type_id = TypeId::of::<T>();
-        let component_type_uuid = match self.component_type_id_to_uuid.get(&component_type_id) {
-            Some(&uuid) => uuid,
-            None => return Box::new(iter::empty()),
-        };
+        let hooks = &self.component_registry.info(component_type_uuid).unwrap().hooks;
+
+        if let (true, Some(on_add)) = (added, &hooks.on_add)
+        {
+            on_add(entity, &mut self.commands.clone());
+        }
 
-        match self.components.get(&component_type_uuid) {
-            Some(components) => Box::new(components.iter::<T>()),
-            None => Box::new(iter::empty()),
+        if let Some(on_insert) = &hooks.on_insert
+        {
+            on_insert(entity, &mut self.commands.clone());
         }
     }
 
-    pub fn iter_components_mut<T: 'static>(&mut self) -> Box<dyn Iterator<Item = (usize, &mut T)> + '_>
+    fn on_component_removed(&mut self, component_type_uuid: ComponentTypeUUID, entity: Entity)
     {
-        let component_type_id = TypeId::of::<T>();
-        let component_type_uuid = match self.component_type_id_to_uuid.get(&component_type_id) {
-            Some(&uuid) => uuid,
-            None => return Box::new(iter::empty()),
-        };
+        self.removed_components.entry(component_type_uuid).or_default().send(entity);
 
-        match self.components.get_mut(&component_type_uuid) {
-            Some(components) => Box::new(components.iter_mut::<T>()),
-            None => Box::new(iter::empty()),
+        if let Some(on_remove) = self.component_registry.info(component_type_uuid).and_then(|info| info.hooks.on_remove.as_ref())
+        {
+            on_remove(entity, &mut self.commands.clone());
         }
     }
 
-    pub fn query<'a, T: ComponentQuery<'a>>(&'a self) -> T::Iter {
-        T::query(self)
+    // Hooks registered here apply to every later add, insert and removal of `T`.
+    pub fn component_hooks<T>(&mut self) -> &mut ComponentHooks where T: 'static
+    {
+        &mut self.register_component::<T>().hooks
     }
 
-    pub fn query_mut<'a, T: ComponentQueryMut<'a>>(&'a mut self) -> T::Iter {
-        T::query_mut(self)
+    pub fn insert_component<T>(&mut self, entity: Entity, value: T) where T: 'static
+    {
+        if !self.has_entity(entity)
+        {
+            return;
+        }
+
+        let component_type_uuid = self.register_component_type::<T>();
+        let mut value = std::mem::ManuallyDrop::new(value);
+
+        // Safety: the entity is alive and the value is moved into the storage.
+        unsafe { self.write_components_raw(entity, &[(component_type_uuid, &mut *value as *mut T as *mut u8)]) };
     }
-}
 
-impl ECS
-{
-    pub fn new() -> Self
+    // Moves the value behind `value` into the storage, or drops it if `entity` is not alive.
+    // Safety: `value` must point to a valid value of the component's layout, which the caller gives up.
+    pub unsafe fn insert_raw(&mut self, entity: Entity, component_type_uuid: ComponentTypeUUID, value: *mut u8)
     {
-        Self
+        let info = self.component_registry.info(component_type_uuid).expect("Component type is not registered");
+
+        if !self.has_entity(entity)
         {
-            storage: ECSStorage::new(),
-            dynamic_systems: HashMap::new(),
+            if let Some(drop_fn) = info.drop_fn()
+            {
+                drop_fn(value);
+            }
+            return;
         }
+
+        self.write_components_raw(entity, &[(component_type_uuid, value)]);
     }
 
-    pub fn create_entity(&mut self) -> EntityUUID
+    pub fn insert_bundle<B>(&mut self, entity: Entity, bundle: B) where B: Bundle
     {
-        self.storage.create_entity()
+        if !self.has_entity(entity)
+        {
+            return;
+        }
+
+        let component_type_uuids = self.bundle_component_types::<B>();
+
+        bundle.write_components(self, entity, &component_type_uuids);
+    }
+
+    pub fn spawn<B>(&mut self, bundle: B) -> Entity where B: Bundle
+    {
+        let entity = self.create_entity();
+        self.insert_bundle(entity, bundle);
+        entity
+    }
+
+    pub fn spawn_batch<B, I>(&mut self, bundles: I) -> Vec<Entity> where B: Bundle, I: IntoIterator<Item = B>
+    {
+        let bundles = bundles.into_iter();
+        let (count, _) = bundles.size_hint();
+
+        let component_type_uuids = self.bundle_component_types::<B>();
+
+        let fresh_entities = count.saturating_sub(self.deleted_entities.len());
+        let max_index = self.entity_uuid_counter.load(Ordering::Relaxed) + fresh_entities;
+
+        self.entity_generations.reserve(fresh_entities);
+        self.entity_locations.reserve(fresh_entities);
+        self.entity_components_bitset.reserve(count);
+
+        for &component_type_uuid in component_type_uuids.iter()
+        {
+            if let Some(components) = self.components.get_mut(&component_type_uuid)
+            {
+                components.reserve(count);
+                components.reserve_index(max_index);
+            }
+        }
+
+        let mut entities = Vec::with_capacity(count);
+
+        for bundle in bundles
+        {
+            let entity = self.create_entity();
+            bundle.write_components(self, entity, &component_type_uuids);
+            entities.push(entity);
+        }
+
+        entities
+    }
+
+    pub fn build_entity(&mut self) -> EntityBuilder<'_>
+    {
+        let entity = self.create_entity();
+        EntityBuilder::new(self, entity)
+    }
+
+    pub fn remove_component<T>(&mut self, entity: Entity) where T: 'static
+    {
+        if !self.has_entity(entity)
+        {
+            return;
+        }
+
+        if let Some(component_type_uuid) = self.component_type_uuid::<T>()
+        {
+            self.remove_component_by_id(entity, component_type_uuid);
+        }
+    }
+
+    pub fn remove_component_by_id(&mut self, entity: Entity, component_type_uuid: ComponentTypeUUID)
+    {
+        let index = entity.index();
+
+        match self.entity_components_bitset.get_mut(&index) {
+            Some(bitset) if self.entity_generations[index] == entity.generation() && bitset.get(component_type_uuid) => bitset.clear(component_type_uuid),
+            _ => return,
+        }
+
+        match self.storage_kind(component_type_uuid) {
+            StorageKind::SparseSet =>
+            {
+                self.components.get_mut(&component_type_uuid).unwrap().remove(index);
+            }
+            StorageKind::Table =>
+            {
+                let location = self.entity_locations[index];
+                let target = self.archetypes.without_component(location.archetype, component_type_uuid, &self.component_registry);
+                let (new_location, moved) = self.archetypes.move_entity(location, target);
+                self.relocate(moved, location);
+                self.entity_locations[index] = new_location;
+            }
+        }
+
+        self.on_component_removed(component_type_uuid, entity);
     }
 
-    pub fn remove_entity(&mut self, uuid: EntityUUID) -> bool
+    // The value and ticks of a component, wherever it is stored.
+    fn component_ptr(&self, entity: Entity, component_type_uuid: ComponentTypeUUID) -> Option<(*mut u8, *mut ComponentTicks)>
     {
-        self.storage.remove_entity(uuid)
+        if !self.has_entity(entity)
+        {
+            return None;
+        }
+
+        match self.component_registry.info(component_type_uuid)?.storage_kind() {
+            StorageKind::SparseSet => self.components.get(&component_type_uuid)?.get_with_ticks_ptr::<u8>(entity.index()),
+            StorageKind::Table =>
+            {
+                let location = self.entity_locations[entity.index()];
+                let column = self.archetypes.get(location.archetype).column(component_type_uuid)?;
+                Some((column.get_ptr(location.row), column.get_ticks_ptr(location.row)))
+            }
+        }
     }
 
-    pub fn has_entity(&self, uuid: EntityUUID) -> bool
+    pub fn get_component<T>(&self, entity: Entity) -> Option<&T> where T: 'static
     {
-        self.storage.has_entity(uuid)
+        let (component, _) = self.component_ptr(entity, self.component_type_uuid::<T>()?)?;
+
+        // Safety: the pointer is to a live `T` that stays put while the storage is borrowed.
+        Some(unsafe { &*(component as *const T) })
     }
 
-    pub fn add_component<T>(&mut self, uuid: EntityUUID) where T: 'static
+    pub fn get_raw(&self, entity: Entity, component_type_uuid: ComponentTypeUUID) -> Option<*const u8>
     {
-        self.storage.add_component::<T>(uuid);
+        self.component_ptr(entity, component_type_uuid).map(|(component, _)| component as *const u8)
     }
 
-    pub fn remove_component<T>(&mut self, uuid: EntityUUID) where T: 'static
+    // Marks the component as changed, like dereferencing a `Mut`.
+    pub fn get_raw_mut(&mut self, entity: Entity, component_type_uuid: ComponentTypeUUID) -> Option<*mut u8>
     {
-        self.storage.remove_component::<T>(uuid);
+        let (component, ticks) = self.component_ptr(entity, component_type_uuid)?;
+
+        // Safety: the storage is mutably borrowed, so nothing else can be looking at these ticks.
+        unsafe { (*ticks).changed = self.change_tick };
+
+        Some(component)
     }
 
-    pub fn get_component<T>(&self, uuid: EntityUUID) -> Option<&T> where T: 'static
+    fn entity_at(generations: &[EntityGeneration], index: EntityUUID) -> Entity
     {
-        self.storage.get_component::<T>(uuid)
+        Entity::new(index, generations[index])
     }
 
-    pub fn iter_components<T: 'static>(&self) -> impl Iterator<Item = (usize, &T)>
+    fn entity_at_index(&self, index: EntityUUID) -> Entity
     {
-        self.storage.iter_components::<T>()
+        Self::entity_at(&self.entity_generations, index)
     }
 
-    pub fn iter_components_mut<T: 'static>(&mut self) -> impl Iterator<Item = (usize, &mut T)>
+    pub fn archetypes(&self) -> &Archetypes
     {
-        self.storage.iter_components_mut::<T>()
+        &self.archetypes
     }
 
-    pub fn entities_count(&self) -> usize
+    pub fn entity_location(&self, entity: Entity) -> Option<EntityLocation>
     {
-        self.storage.entity_components_bitset.len()
+        self.has_entity(entity).then(|| self.entity_locations[entity.index()])
     }
 
-    pub fn storage(&self) -> &ECSStorage
+    pub fn iter_components<T: 'static>(&self) -> Box<dyn Iterator<Item = (Entity, &T)> + '_>
     {
-        &self.storage
+        Box::new(self.query::<(&T,)>())
     }
 
-    pub fn storage_mut(&mut self) -> &mut ECSStorage
+    pub fn iter_components_mut<T: 'static>(&mut self) -> Box<dyn Iterator<Item = (Entity, Mut<'_, T>)> + '_>
     {
-        &mut self.storage
+        Box::new(self.query_mut::<(&mut T,)>())
     }
 
-    pub fn register_system<TSystem>(&mut self) where TSystem: System + 'static
+    pub fn change_tick(&self) -> Tick
     {
-        self.dynamic_systems.insert(TypeId::of::<TSystem>(), Box::new(TSystem::new()));
+        self.change_tick
     }
 
-    pub fn start(&mut self)
+    pub fn task_pool(&self) -> &TaskPool
+    {
+        self.task_pool.get_or_init(TaskPool::new)
+    }
+
+    pub fn set_task_pool(&mut self, task_pool: TaskPool)
+    {
+        self.task_pool = OnceLock::from(task_pool);
+    }
+
+    // The tick `Added` and `Changed` compare against: the last run of the running `System` impl,
+    // or the start of the latest `ECS::update` outside of systems.
+    pub fn last_change_tick(&self) -> Tick
+    {
+        self.last_change_tick
+    }
+
+    // The ticks queries created directly on the storage run with.
+    pub fn system_ticks(&self) -> SystemTicks
     {
-        for system in self.dynamic_systems.values()
+        SystemTicks
         {
-            system.start(&mut self.storage);
+            last_change_tick: self.last_change_tick,
+            change_tick: self.change_tick,
         }
     }
 
-    pub fn update(&mut self)
+    pub fn set_last_change_tick(&mut self, last_change_tick: Tick)
+    {
+        self.last_change_tick = last_change_tick;
+    }
+
+    // Returns the tick that was current until now.
+    pub fn increment_change_tick(&mut self) -> Tick
+    {
+        self.change_tick += 1;
+        self.change_tick - 1
+    }
+
+    pub fn removed_component_log<T>(&self) -> Option<&EntityLog> where T: 'static
+    {
+        self.component_type_uuid::<T>().and_then(|component_type_uuid| self.removed_components.get(&component_type_uuid))
+    }
+
+    pub fn despawned_entities(&self) -> &EntityLog
+    {
+        &self.despawned_entities
+    }
+
+    // Drops the removals and despawns every live reader has read.
+    pub fn trim_removal_logs(&mut self)
     {
-        for system in self.dynamic_systems.values()
+        for log in self.removed_components.values_mut()
         {
-            system.update(&mut self.storage);
+            log.trim();
         }
+
+        self.despawned_entities.trim();
     }
 
-    pub fn fixed_update(&mut self)
+    // Ages every `Events<E>` added with `add_event`; anything older than the previous update is dropped.
+    pub fn update_events(&mut self)
     {
-        for system in self.dynamic_systems.values()
+        for (type_id, update) in &self.event_updates
         {
-            system.fixed_update(&mut self.storage);
+            if let Some(events) = self.resources.get_mut(type_id)
+            {
+                update(events.get_mut().as_mut());
+            }
         }
     }
 
-    pub fn render(&mut self)
+    // Stores `Events<E>` as a resource that `update_events` ages, so `EventReader<E>` and
+    // `EventWriter<E>` system parameters can reach it.
+    pub fn add_event<E>(&mut self) where E: 'static
     {
-        for system in self.dynamic_systems.values()
+        if !self.has_resource::<Events<E>>()
         {
-            system.render(&mut self.storage);
+            self.insert_resource(Events::<E>::new());
         }
+
+        self.event_updates.insert(TypeId::of::<Events<E>>(), event::update_erased::<E>);
     }
 
-    pub fn serialize<T: serde::Serialize + 'static>(&self) -> Result<String, serde_json::Error>
+    pub fn insert_resource<R>(&mut self, resource: R) -> Option<R> where R: 'static
     {
-        self.storage.query::<(&T,)>().map(|(entity, component)| {
-            serde_json::to_string(&component)
-        }).collect()
+        self.resources.insert(TypeId::of::<R>(), UnsafeCell::new(Box::new(resource))).map(|previous| *previous.into_inner().downcast::<R>().unwrap())
+    }
+
+    pub fn remove_resource<R>(&mut self) -> Option<R> where R: 'static
+    {
+        self.resources.remove(&TypeId::of::<R>()).map(|resource| *resource.into_inner().downcast::<R>().unwrap())
     }
-}
\ No newline at end of file
+
+    pub fn has_resource<R>(&self) -> bool where R: 'static
+    {
+        self.resources.contains_key(&TypeId::of::<R>())
+    }
+
+    pub fn resource<R>(&self) -> Option<&R> where R: 'static
+    {
+        // Safety: mutable borrows from a shared storage only exist inside systems that do not read `R` otherwise.
+        self.resources.get(&TypeId::of::<R>()).and_then(|resource| unsafe { &*resource.get() }.downcast_ref::<R>())
+    }
+
+    pub fn resource_mut<R>(&mut self) -> Option<&mut R> where R: 'static
+    {
+        self.resources.get_mut(&TypeId::of::<R>()).and_then(|resource| resource.get_mut().downcast_mut::<R>())
+    }
+
+    // Safety: the caller must ensure no other reference to `R` is alive while the result is.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn resource_unchecked_mut<R>(&self) -> Option<&mut R> where R: 'static
+    {
+        self.resources.get(&TypeId::of::<R>()).and_then(|resource| (*resource.get()).downcast_mut::<R>())
+    }
+
+    pub fn commands(&self) -> Commands
+    {
+        self.commands.clone()
+    }
+
+    pub fn apply_commands(&mut self)
+    {
+        // Commands may record further commands, so keep draining until the queue stays empty.
+        loop
+        {
+            let commands = self.commands.take();
+            if commands.is_empty()
+            {
+                break;
+            }
+
+            for command in commands
+            {
+                command(self);
+            }
+        }
+    }
+
+    pub fn query<'a, T: ComponentQuery<'a>>(&'a self) -> T::Iter {
+        T::query(self)
+    }
+
+    pub fn query_mut<'a, T: ComponentQueryMut<'a>>(&'a mut self) -> T::Iter {
+        T::query_mut(self)
+    }
+
+    pub fn query_filtered<'a, T, F>(&'a self) -> QueryIter<'a, T, F> where T: ReadOnlyQueryTuple<'a>, F: QueryFilter<'a>
+    {
+        // Safety: `T` is read-only, so a shared borrow is enough.
+        unsafe { QueryIter::new(self) }
+    }
+
+    pub fn query_filtered_mut<'a, T, F>(&'a mut self) -> QueryIter<'a, T, F> where T: QueryTuple<'a>, F: QueryFilter<'a>
+    {
+        // Safety: the storage is mutably borrowed for `'a`.
+        unsafe { QueryIter::new(self) }
+    }
+}
+
+impl Default for ECSStorage
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl ECS
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            storage: ECSStorage::new(),
+            schedules: HashMap::new(),
+            executor: ExecutorKind::default(),
+        }
+    }
+
+    pub fn create_entity(&mut self) -> Entity
+    {
+        self.storage.create_entity()
+    }
+
+    pub fn remove_entity(&mut self, entity: Entity) -> bool
+    {
+        self.storage.remove_entity(entity)
+    }
+
+    pub fn has_entity(&self, entity: Entity) -> bool
+    {
+        self.storage.has_entity(entity)
+    }
+
+    pub fn add_component<T>(&mut self, entity: Entity) where T: Component
+    {
+        self.storage.add_component::<T>(entity);
+    }
+
+    pub fn insert_component<T>(&mut self, entity: Entity, value: T) where T: 'static
+    {
+        self.storage.insert_component(entity, value);
+    }
+
+    pub fn insert_bundle<B>(&mut self, entity: Entity, bundle: B) where B: Bundle
+    {
+        self.storage.insert_bundle(entity, bundle);
+    }
+
+    pub fn spawn<B>(&mut self, bundle: B) -> Entity where B: Bundle
+    {
+        self.storage.spawn(bundle)
+    }
+
+    pub fn spawn_batch<B, I>(&mut self, bundles: I) -> Vec<Entity> where B: Bundle, I: IntoIterator<Item = B>
+    {
+        self.storage.spawn_batch(bundles)
+    }
+
+    pub fn build_entity(&mut self) -> EntityBuilder<'_>
+    {
+        self.storage.build_entity()
+    }
+
+    pub fn remove_component<T>(&mut self, entity: Entity) where T: 'static
+    {
+        self.storage.remove_component::<T>(entity);
+    }
+
+    pub fn register_component<T>(&mut self) -> &mut ComponentInfo where T: 'static
+    {
+        self.storage.register_component::<T>()
+    }
+
+    pub fn component_hooks<T>(&mut self) -> &mut ComponentHooks where T: 'static
+    {
+        self.storage.component_hooks::<T>()
+    }
+
+    pub fn register_dynamic_component(&mut self, name: impl Into<Cow<'static, str>>, layout: Layout, drop_fn: Option<DropFn>) -> Result<ComponentTypeUUID, RegistryError>
+    {
+        self.storage.register_dynamic_component(name, layout, drop_fn)
+    }
+
+    pub fn get_component<T>(&self, entity: Entity) -> Option<&T> where T: 'static
+    {
+        self.storage.get_component::<T>(entity)
+    }
+
+    pub fn iter_components<T: 'static>(&self) -> impl Iterator<Item = (Entity, &T)>
+    {
+        self.storage.iter_components::<T>()
+    }
+
+    pub fn iter_components_mut<T: 'static>(&mut self) -> impl Iterator<Item = (Entity, Mut<'_, T>)>
+    {
+        self.storage.iter_components_mut::<T>()
+    }
+
+    pub fn entities_count(&self) -> usize
+    {
+        self.storage.entities_count()
+    }
+
+    pub fn storage(&self) -> &ECSStorage
+    {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut ECSStorage
+    {
+        &mut self.storage
+    }
+
+    pub fn insert_resource<R>(&mut self, resource: R) -> Option<R> where R: 'static
+    {
+        self.storage.insert_resource(resource)
+    }
+
+    pub fn remove_resource<R>(&mut self) -> Option<R> where R: 'static
+    {
+        self.storage.remove_resource::<R>()
+    }
+
+    pub fn has_resource<R>(&self) -> bool where R: 'static
+    {
+        self.storage.has_resource::<R>()
+    }
+
+    pub fn resource<R>(&self) -> Option<&R> where R: 'static
+    {
+        self.storage.resource::<R>()
+    }
+
+    pub fn resource_mut<R>(&mut self) -> Option<&mut R> where R: 'static
+    {
+        self.storage.resource_mut::<R>()
+    }
+
+    pub fn commands(&self) -> Commands
+    {
+        self.storage.commands()
+    }
+
+    pub fn apply_commands(&mut self)
+    {
+        self.storage.apply_commands();
+    }
+
+    // Adds the system to the four built-in schedules.
+    pub fn register_system<TSystem>(&mut self) -> SystemConfig<'_> where TSystem: System + 'static
+    {
+        let system = Rc::new(RefCell::new(RegisteredSystem::new(Box::new(TSystem::new()))));
+        let phases: [(TypeId, Phase); 4] =
+        [
+            (TypeId::of::<Start>(),       |system, storage| system.start(storage)),
+            (TypeId::of::<Update>(),      |system, storage| system.update(storage)),
+            (TypeId::of::<FixedUpdate>(), |system, storage| system.fixed_update(storage)),
+            (TypeId::of::<Render>(),      |system, storage| system.render(storage)),
+        ];
+
+        let mut indices = HashMap::new();
+        for (label, phase) in phases
+        {
+            let kind = SystemKind::Phase(PhaseSystem::new(system.clone(), phase));
+            indices.insert(label, self.schedule_by_id(label).insert(TypeId::of::<TSystem>(), type_name::<TSystem>(), kind));
+        }
+
+        SystemConfig::new(self.schedules.iter_mut().filter_map(|(label, schedule)| indices.get(label).map(|&index| (schedule, index))).collect())
+    }
+
+    // Adds a function system to `Update`.
+    pub fn add_system<Marker, S>(&mut self, system: S) -> SystemConfig<'_> where S: IntoSystem<Marker> + 'static
+    {
+        self.add_system_to(Update, system)
+    }
+
+    pub fn add_system_to<L, Marker, S>(&mut self, _label: L, system: S) -> SystemConfig<'_> where L: ScheduleLabel, S: IntoSystem<Marker> + 'static
+    {
+        let mut system = system.into_system();
+        system.initialize(&mut self.storage);
+
+        let schedule = self.schedule_by_id(TypeId::of::<L>());
+        let index = schedule.insert(TypeId::of::<S>(), type_name::<S>(), SystemKind::Function(Box::new(system)));
+        SystemConfig::new(vec![(schedule, index)])
+    }
+
+    pub fn add_event<E>(&mut self) where E: 'static
+    {
+        self.storage.add_event::<E>();
+    }
+
+    // Creates the schedule if it does not exist yet.
+    pub fn schedule_mut<L>(&mut self, _label: L) -> &mut Schedule where L: ScheduleLabel
+    {
+        self.schedule_by_id(TypeId::of::<L>())
+    }
+
+    fn schedule_by_id(&mut self, label: TypeId) -> &mut Schedule
+    {
+        let executor = self.executor;
+        self.schedules.entry(label).or_insert_with(|| Schedule::with_executor(executor))
+    }
+
+    // The system names of a schedule in the order they run, or the cycle that prevents ordering them.
+    pub fn system_order<L>(&mut self, _label: L) -> Result<Vec<&'static str>, ScheduleError> where L: ScheduleLabel
+    {
+        self.schedules.get_mut(&TypeId::of::<L>()).map_or(Ok(Vec::new()), Schedule::order)
+    }
+
+    // Applies to every schedule, including the ones created later.
+    pub fn set_executor(&mut self, executor: ExecutorKind)
+    {
+        self.executor = executor;
+
+        for schedule in self.schedules.values_mut()
+        {
+            schedule.set_executor(executor);
+        }
+    }
+
+    // Commands recorded outside of systems, e.g. by direct calls on the storage, are applied before the
+    // first system runs, the commands of every system before the next one, and whatever is left once the
+    // schedule is done. A label no system was added to only applies the commands.
+    pub fn run_schedule<L>(&mut self, _label: L) where L: ScheduleLabel
+    {
+        self.storage.apply_commands();
+
+        if let Some(schedule) = self.schedules.get_mut(&TypeId::of::<L>())
+        {
+            schedule.run(&mut self.storage);
+        }
+
+        self.storage.apply_commands();
+    }
+
+    pub fn start(&mut self)
+    {
+        self.run_schedule(Start);
+    }
+
+    // Advances the world tick, trims the removal logs and ages the events before running `Update`. Outside of
+    // `System` impls, `Added` and `Changed` then compare against the start of this update.
+    pub fn update(&mut self)
+    {
+        let last_update_tick = self.storage.increment_change_tick();
+        self.storage.set_last_change_tick(last_update_tick);
+
+        self.storage.trim_removal_logs();
+        self.storage.update_events();
+
+        self.run_schedule(Update);
+    }
+
+    pub fn fixed_update(&mut self)
+    {
+        self.run_schedule(FixedUpdate);
+    }
+
+    pub fn render(&mut self)
+    {
+        self.run_schedule(Render);
+    }
+
+    pub fn serialize<T: serde::Serialize + 'static>(&self) -> Result<String, serde_json::Error>
+    {
+        self.storage.query::<(&T,)>().map(|(_, component)| {
+            serde_json::to_string(&component)
+        }).collect()
+    }
+}
+
+impl Default for ECS
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::{alloc::Layout, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}};
+
+    use super::{change_detection::Tick, component::registry::StorageKind, query::filter::{Added, Changed, With, Without}, removal_detection::RemovedComponents, system::System, ECSStorage, ECS};
+
+    static SEEN_ENTITIES: AtomicUsize = AtomicUsize::new(0);
+
+    // Records how many entities exist when it runs.
+    struct CountEntities;
+
+    impl System for CountEntities
+    {
+        fn new() -> Self
+        {
+            Self
+        }
+
+        fn update(&self, storage: &mut ECSStorage)
+        {
+            SEEN_ENTITIES.store(storage.entities_count(), Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn stale_entities_are_rejected_after_their_index_is_recycled()
+    {
+        let mut storage = ECSStorage::new();
+        let stale = storage.create_entity();
+        storage.add_component::<u32>(stale);
+        assert!(storage.remove_entity(stale));
+
+        let fresh = storage.create_entity();
+        storage.add_component::<u32>(fresh);
+        assert_eq!(fresh.index(), stale.index());
+        assert!(fresh.generation() > stale.generation());
+
+        assert!(!storage.has_entity(stale));
+        assert!(storage.get_component::<u32>(stale).is_none());
+        assert!(!storage.remove_entity(stale));
+
+        // Writes through the stale handle must not reach the entity that took over its index.
+        storage.add_component::<u64>(stale);
+        storage.remove_component::<u32>(stale);
+
+        assert!(storage.has_entity(fresh));
+        assert!(storage.get_component::<u32>(fresh).is_some());
+        assert!(storage.get_component::<u64>(fresh).is_none());
+        assert_eq!(storage.query::<(&u32,)>().map(|(entity, _)| entity).collect::<Vec<_>>(), vec![fresh]);
+    }
+
+    #[test]
+    fn inserted_values_are_stored_and_overwritten()
+    {
+        let mut storage = ECSStorage::new();
+        let entity = storage.create_entity();
+
+        storage.insert_component(entity, String::from("first"));
+        storage.add_component::<u32>(entity);
+        assert_eq!(storage.get_component::<String>(entity).map(String::as_str), Some("first"));
+        assert_eq!(storage.get_component::<u32>(entity), Some(&0));
+
+        storage.insert_component(entity, String::from("second"));
+        assert_eq!(storage.get_component::<String>(entity).map(String::as_str), Some("second"));
+    }
+
+    #[test]
+    fn bundles_and_builders_spawn_their_components()
+    {
+        let mut storage = ECSStorage::new();
+        let spawned = storage.spawn((1u32, String::from("bundle")));
+        let built = storage.build_entity().with(2u32).with_bundle((String::from("builder"), 3u64)).build();
+
+        assert_eq!(storage.get_component::<u32>(spawned), Some(&1));
+        assert_eq!(storage.get_component::<String>(spawned).map(String::as_str), Some("bundle"));
+        assert_eq!(storage.get_component::<u64>(spawned), None);
+
+        assert_eq!(storage.get_component::<u32>(built), Some(&2));
+        assert_eq!(storage.get_component::<String>(built).map(String::as_str), Some("builder"));
+        assert_eq!(storage.get_component::<u64>(built), Some(&3));
+    }
+
+    #[test]
+    fn spawn_batch_spawns_every_bundle()
+    {
+        let mut storage = ECSStorage::new();
+        let removed = storage.spawn((0u32,));
+        storage.remove_entity(removed);
+
+        let entities = storage.spawn_batch((0..100u32).map(|i| (i, u64::from(i) * 2)));
+
+        assert_eq!(entities.len(), 100);
+        assert_eq!(storage.entities_count(), 100);
+        for (i, &entity) in (0..100u32).zip(&entities)
+        {
+            assert_eq!(storage.get_component::<u32>(entity), Some(&i));
+            assert_eq!(storage.get_component::<u64>(entity), Some(&(u64::from(i) * 2)));
+        }
+    }
+
+    #[test]
+    fn later_commands_can_use_reserved_entities()
+    {
+        let mut storage = ECSStorage::new();
+        storage.spawn((0u32,));
+
+        let mut commands = storage.commands();
+        let entity = commands.spawn_empty();
+        commands.insert(entity, 1u32);
+        commands.insert_bundle(entity, (String::from("reserved"),));
+        assert!(!storage.has_entity(entity));
+
+        storage.apply_commands();
+        assert!(storage.has_entity(entity));
+        assert_eq!(storage.get_component::<u32>(entity), Some(&1));
+        assert_eq!(storage.get_component::<String>(entity).map(String::as_str), Some("reserved"));
+
+        // Reserved indices are not handed out again by direct spawns.
+        assert_ne!(storage.spawn((2u32,)), entity);
+    }
+
+    #[test]
+    fn commands_apply_in_order()
+    {
+        let mut storage = ECSStorage::new();
+        let kept = storage.spawn((0u32,));
+        let despawned = storage.spawn((0u32,));
+
+        let mut commands = storage.commands();
+        commands.insert(kept, 1u32);
+        commands.remove::<u32>(kept);
+        commands.insert(kept, 2u32);
+        commands.despawn(despawned);
+        commands.insert(despawned, 3u32);
+        commands.add(move |storage| assert_eq!(storage.get_component::<u32>(kept), Some(&2)));
+
+        storage.apply_commands();
+        assert_eq!(storage.get_component::<u32>(kept), Some(&2));
+        assert!(!storage.has_entity(despawned));
+        assert!(storage.commands().is_empty());
+    }
+
+    #[test]
+    fn commands_recorded_outside_systems_apply_before_the_first_system()
+    {
+        let mut ecs = ECS::new();
+        ecs.commands().spawn((1u32,));
+        ecs.update();
+        assert_eq!(ecs.entities_count(), 1);
+
+        ecs.register_system::<CountEntities>();
+        ecs.commands().spawn((2u32,));
+        ecs.update();
+        assert_eq!(SEEN_ENTITIES.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn resources_are_inserted_read_and_removed()
+    {
+        let mut storage = ECSStorage::new();
+        assert!(!storage.has_resource::<u32>());
+        assert_eq!(storage.resource::<u32>(), None);
+        assert_eq!(storage.remove_resource::<u32>(), None);
+
+        assert_eq!(storage.insert_resource(1u32), None);
+        *storage.resource_mut::<u32>().unwrap() += 1;
+        assert_eq!(storage.resource::<u32>(), Some(&2));
+        assert_eq!(storage.insert_resource(3u32), Some(2));
+
+        // Resources are keyed by type, so other types are unaffected.
+        assert_eq!(storage.resource::<u64>(), None);
+
+        storage.commands().remove_resource::<u32>();
+        storage.commands().insert_resource(String::from("deferred"));
+        storage.apply_commands();
+        assert!(!storage.has_resource::<u32>());
+        assert_eq!(storage.remove_resource::<String>().as_deref(), Some("deferred"));
+        assert!(!storage.has_resource::<String>());
+    }
+
+    // Stores the baseline the storage saw during `update`.
+    struct SeenBaseline;
+
+    impl System for SeenBaseline
+    {
+        fn new() -> Self
+        {
+            Self
+        }
+
+        fn update(&self, storage: &mut ECSStorage)
+        {
+            storage.insert_resource(storage.last_change_tick());
+        }
+    }
+
+    fn changed(ecs: &ECS) -> usize
+    {
+        ecs.storage().query_filtered::<(&u32,), (Changed<u32>,)>().count()
+    }
+
+    #[test]
+    fn update_advances_the_world_tick_without_systems()
+    {
+        let mut ecs = ECS::new();
+        let before = ecs.storage().change_tick();
+
+        ecs.update();
+        ecs.update();
+
+        assert_eq!(ecs.storage().change_tick(), before + 2);
+    }
+
+    #[test]
+    fn direct_queries_see_changes_since_the_last_update()
+    {
+        let mut ecs = ECS::new();
+        ecs.spawn((0u32,));
+        assert_eq!(changed(&ecs), 1);
+
+        ecs.update();
+        assert_eq!(changed(&ecs), 0);
+
+        ecs.storage_mut().query_mut::<(&mut u32,)>().for_each(|(_, mut value)| *value += 1);
+        assert_eq!(changed(&ecs), 1);
+
+        ecs.update();
+        assert_eq!(changed(&ecs), 0);
+    }
+
+    #[test]
+    fn system_runs_restore_the_baseline()
+    {
+        let mut ecs = ECS::new();
+        ecs.register_system::<SeenBaseline>();
+        ecs.spawn((0u32,));
+        ecs.update();
+
+        let update_start = ecs.storage().change_tick();
+        ecs.update();
+
+        // The system compared against its run in the previous update, direct queries against
+        // the start of this one.
+        assert!(ecs.resource::<Tick>().is_some_and(|&seen| seen < update_start));
+        assert_eq!(ecs.storage().last_change_tick(), update_start);
+        assert_eq!(changed(&ecs), 0);
+    }
+
+    // Records which hooks of `u32` fired, in order.
+    fn record_hooks(storage: &mut ECSStorage) -> Arc<Mutex<Vec<&'static str>>>
+    {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let (on_add, on_insert, on_remove) = (fired.clone(), fired.clone(), fired.clone());
+
+        storage.component_hooks::<u32>()
+            .on_add(move |_, _| on_add.lock().unwrap().push("add"))
+            .on_insert(move |_, _| on_insert.lock().unwrap().push("insert"))
+            .on_remove(move |_, _| on_remove.lock().unwrap().push("remove"));
+
+        fired
+    }
+
+    fn take(fired: &Mutex<Vec<&'static str>>) -> Vec<&'static str>
+    {
+        std::mem::take(&mut *fired.lock().unwrap())
+    }
+
+    #[test]
+    fn hooks_fire_on_add_insert_and_remove()
+    {
+        let mut storage = ECSStorage::new();
+        let fired = record_hooks(&mut storage);
+        let entity = storage.spawn((0u64,));
+
+        storage.insert_component(entity, 1u32);
+        assert_eq!(take(&fired), ["add", "insert"]);
+
+        // Replacing a value is not an add.
+        storage.insert_component(entity, 2u32);
+        assert_eq!(take(&fired), ["insert"]);
+
+        storage.remove_component::<u32>(entity);
+        assert_eq!(take(&fired), ["remove"]);
+
+        storage.remove_component::<u32>(entity);
+        assert!(take(&fired).is_empty());
+
+        // Despawning removes every component, firing `on_remove` once.
+        storage.insert_component(entity, 3u32);
+        take(&fired);
+        storage.remove_entity(entity);
+        assert_eq!(take(&fired), ["remove"]);
+
+        // Components of other types do not fire the hooks of `u32`.
+        storage.spawn((0u64,));
+        assert!(take(&fired).is_empty());
+    }
+
+    #[test]
+    fn hook_commands_are_applied()
+    {
+        let mut ecs = ECS::new();
+        ecs.component_hooks::<u32>().on_add(|entity, commands| commands.insert(entity, 1u64));
+
+        // Hooks fired by direct calls record their commands for the next flush.
+        let entity = ecs.spawn((0u32,));
+        assert_eq!(ecs.get_component::<u64>(entity), None);
+
+        ecs.update();
+        assert_eq!(ecs.get_component::<u64>(entity), Some(&1));
+    }
+
+    #[test]
+    fn dynamic_components_are_stored_next_to_typed_ones()
+    {
+        let mut storage = ECSStorage::new();
+        let entity = storage.spawn((1u32,));
+
+        // A dynamic component may be named like a Rust type without replacing it.
+        let health = storage.register_dynamic_component(std::any::type_name::<u32>(), Layout::new::<u64>(), None).unwrap();
+        assert!(storage.register_dynamic_component(std::any::type_name::<u32>(), Layout::new::<u64>(), None).is_err());
+
+        let mut value = 100u64;
+        unsafe { storage.insert_raw(entity, health, &mut value as *mut u64 as *mut u8) };
+        storage.insert_component(entity, 2u32);
+
+        assert_eq!(storage.get_raw(entity, health).map(|ptr| unsafe { *(ptr as *const u64) }), Some(100));
+        assert_eq!(storage.get_component::<u32>(entity), Some(&2));
+
+        assert!(storage.remove_entity(entity));
+        assert!(storage.get_raw(entity, health).is_none());
+    }
+
+    struct Player;
+
+    #[test]
+    fn queries_and_filters_see_tags()
+    {
+        let mut ecs = ECS::new();
+        let player = ecs.spawn((Player, 1u32));
+        ecs.spawn((2u32,));
+
+        let players = |ecs: &ECS| ecs.storage().query::<(&Player,)>().map(|(entity, _)| entity).collect::<Vec<_>>();
+        assert_eq!(players(&ecs), [player]);
+        assert_eq!(ecs.storage().query_filtered::<(&u32,), (With<Player>,)>().map(|(_, value)| *value).collect::<Vec<_>>(), [1]);
+        assert_eq!(ecs.storage().query_filtered::<(&u32,), (Without<Player>,)>().map(|(_, value)| *value).collect::<Vec<_>>(), [2]);
+
+        let added = |ecs: &ECS| ecs.storage().query_filtered::<(&Player,), (Added<Player>,)>().count();
+        let changed = |ecs: &ECS| ecs.storage().query_filtered::<(&Player,), (Changed<Player>,)>().count();
+        assert_eq!((added(&ecs), changed(&ecs)), (1, 1));
+
+        ecs.update();
+        assert_eq!((added(&ecs), changed(&ecs)), (0, 0));
+
+        // Re-inserting a tag changes it without adding it again.
+        ecs.storage_mut().insert_component(player, Player);
+        assert_eq!((added(&ecs), changed(&ecs)), (0, 1));
+
+        ecs.update();
+        ecs.storage_mut().query_mut::<(&mut Player,)>().for_each(|(_, mut tag)| tag.set_changed());
+        assert_eq!((added(&ecs), changed(&ecs)), (0, 1));
+
+        ecs.storage_mut().remove_component::<Player>(player);
+        assert!(players(&ecs).is_empty());
+    }
+
+    static DROPPED_HEALTH: AtomicUsize = AtomicUsize::new(0);
+
+    struct Health(u32);
+
+    impl Drop for Health
+    {
+        fn drop(&mut self)
+        {
+            DROPPED_HEALTH.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Position(u32);
+
+    #[test]
+    fn table_moves_keep_values_and_drop_them_once()
+    {
+        let mut ecs = ECS::new();
+        let storage = ecs.storage_mut();
+        for component_type_uuid in [storage.register_component::<Health>().id(), storage.register_component::<Position>().id()]
+        {
+            storage.set_storage_kind(component_type_uuid, StorageKind::Table);
+        }
+
+        let entities: Vec<_> = (0..4).map(|i| storage.spawn((Health(i), i))).collect();
+
+        // Moves rows into a later archetype, back into the earlier one, and past sparse set changes,
+        // leaving holes that other entities get swapped into.
+        for &entity in entities.iter().step_by(2)
+        {
+            storage.insert_component(entity, Position(10));
+        }
+        storage.remove_component::<Position>(entities[0]);
+        storage.remove_component::<u32>(entities[1]);
+        storage.insert_component(entities[1], 1u32);
+
+        for (i, &entity) in (0..4u32).zip(&entities)
+        {
+            assert_eq!(storage.get_component::<Health>(entity).map(|health| health.0), Some(i));
+            assert_eq!(storage.get_component::<u32>(entity), Some(&i));
+        }
+        assert_eq!(storage.get_component::<Position>(entities[2]).map(|position| position.0), Some(10));
+        assert_eq!(DROPPED_HEALTH.load(Ordering::SeqCst), 0);
+
+        storage.insert_component(entities[3], Health(30));
+        assert_eq!(DROPPED_HEALTH.load(Ordering::SeqCst), 1);
+
+        for &entity in &entities
+        {
+            storage.remove_entity(entity);
+        }
+        assert_eq!(DROPPED_HEALTH.load(Ordering::SeqCst), 5);
+
+        // Once no entity has it, the component can go back to a sparse set and keep working.
+        let health = storage.component_type_uuid::<Health>().unwrap();
+        storage.set_storage_kind(health, StorageKind::SparseSet);
+        let entity = storage.spawn((Health(7), Position(8)));
+        assert_eq!(storage.get_component::<Health>(entity).map(|health| health.0), Some(7));
+        assert_eq!(storage.get_component::<Position>(entity).map(|position| position.0), Some(8));
+
+        storage.remove_entity(entity);
+        assert_eq!(DROPPED_HEALTH.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn table_components_work_with_queries_hooks_and_removal_logs()
+    {
+        let mut ecs = ECS::new();
+        let position = ecs.storage_mut().register_component::<Position>().id();
+        ecs.storage_mut().set_storage_kind(position, StorageKind::Table);
+
+        let mut removed = RemovedComponents::<Position>::new();
+        removed.read(ecs.storage()).for_each(drop);
+        ecs.component_hooks::<Position>().on_remove(|entity, commands| commands.insert(entity, 0u64));
+
+        let entities = ecs.storage_mut().spawn_batch((0..3).map(|i| (Position(i), i)));
+        let mut values: Vec<_> = ecs.storage().query::<(&Position, &u32)>().map(|(_, position, value)| (position.0, *value)).collect();
+        values.sort();
+        assert_eq!(values, [(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(ecs.storage().query_filtered::<(&u32,), (With<Position>,)>().count(), 3);
+
+        ecs.storage_mut().remove_component::<Position>(entities[1]);
+        ecs.apply_commands();
+        assert_eq!(removed.read(ecs.storage()).collect::<Vec<_>>(), [entities[1]]);
+        assert_eq!(ecs.get_component::<u64>(entities[1]), Some(&0));
+        assert_eq!(ecs.storage().query_filtered::<(&u32,), (Without<Position>,)>().map(|(_, value)| *value).collect::<Vec<_>>(), [1]);
+    }
+}
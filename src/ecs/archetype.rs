@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+
+use crate::data_structures::{bit_set::BitSet, type_erased_vec::TypeErasedVec};
+
+use super::{change_detection::{ComponentTicks, Tick}, component::{registry::ComponentRegistry, ComponentTypeUUID}, entity::EntityUUID};
+
+pub type ArchetypeId = usize;
+
+// The archetype every entity starts in, holding no table components.
+pub const EMPTY_ARCHETYPE: ArchetypeId = 0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntityLocation
+{
+    pub archetype: ArchetypeId,
+    pub row: usize,
+}
+
+// One table component of an archetype, one item per row.
+pub struct Column
+{
+    data: TypeErasedVec,
+    ticks: TypeErasedVec,
+}
+
+impl Column
+{
+    fn new(data: TypeErasedVec) -> Self
+    {
+        Self
+        {
+            data,
+            ticks: TypeErasedVec::new::<ComponentTicks>(),
+        }
+    }
+
+    // Moves the value behind `value` into a new row.
+    unsafe fn push(&mut self, value: *const u8, ticks: ComponentTicks)
+    {
+        self.data.push_raw(value);
+        self.ticks.push(ticks);
+    }
+
+    unsafe fn replace(&mut self, row: usize, value: *const u8, tick: Tick)
+    {
+        self.data.replace_raw(row, value);
+        self.ticks.get_typed_mut::<ComponentTicks>(row).changed = tick;
+    }
+
+    fn swap_remove(&mut self, row: usize)
+    {
+        self.data.remove_swap_with_last(row);
+        self.ticks.remove_swap_with_last(row);
+    }
+
+    // Appends the value at `row` to `destination` and closes the gap without dropping it.
+    fn move_row(&mut self, row: usize, destination: &mut Column)
+    {
+        unsafe
+        {
+            destination.push(self.data.get_ptr(row), *self.ticks.get_typed::<ComponentTicks>(row));
+            self.data.forget_swap_with_last(row);
+        }
+        self.ticks.remove_swap_with_last(row);
+    }
+
+    // Same contract as `TypeErasedVec::get_ptr`.
+    pub fn get_ptr(&self, row: usize) -> *mut u8
+    {
+        self.data.get_ptr(row)
+    }
+
+    pub fn get_ticks_ptr(&self, row: usize) -> *mut ComponentTicks
+    {
+        self.ticks.get_ptr(row) as *mut ComponentTicks
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.data.is_empty()
+    }
+}
+
+// All entities with exactly the same set of table components, packed so that row `n` of every
+// column belongs to `entities[n]`.
+pub struct Archetype
+{
+    id: ArchetypeId,
+    component_type_uuids: Box<[ComponentTypeUUID]>,
+    components: BitSet,
+    columns: HashMap<ComponentTypeUUID, Column>,
+    entities: Vec<EntityUUID>,
+    add_edges: HashMap<ComponentTypeUUID, ArchetypeId>,
+    remove_edges: HashMap<ComponentTypeUUID, ArchetypeId>,
+}
+
+impl Archetype
+{
+    fn new(id: ArchetypeId, component_type_uuids: Box<[ComponentTypeUUID]>, registry: &ComponentRegistry) -> Self
+    {
+        let mut components = BitSet::new();
+        let mut columns = HashMap::new();
+
+        for &component_type_uuid in component_type_uuids.iter()
+        {
+            let info = registry.info(component_type_uuid).unwrap();
+            components.set(component_type_uuid);
+            columns.insert(component_type_uuid, Column::new(TypeErasedVec::from_layout(info.layout(), info.drop_fn())));
+        }
+
+        Self
+        {
+            id,
+            component_type_uuids,
+            components,
+            columns,
+            entities: Vec::new(),
+            add_edges: HashMap::new(),
+            remove_edges: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> ArchetypeId
+    {
+        self.id
+    }
+
+    pub fn component_type_uuids(&self) -> &[ComponentTypeUUID]
+    {
+        &self.component_type_uuids
+    }
+
+    pub fn contains(&self, component_type_uuid: ComponentTypeUUID) -> bool
+    {
+        self.components.get(component_type_uuid)
+    }
+
+    pub fn column(&self, component_type_uuid: ComponentTypeUUID) -> Option<&Column>
+    {
+        self.columns.get(&component_type_uuid)
+    }
+
+    pub fn entities(&self) -> &[EntityUUID]
+    {
+        &self.entities
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entities.is_empty()
+    }
+
+    // Returns the entity that was moved into `row` to fill the gap, if any.
+    fn swap_remove_entity(&mut self, row: usize) -> Option<EntityUUID>
+    {
+        self.entities.swap_remove(row);
+        self.entities.get(row).copied()
+    }
+}
+
+pub struct Archetypes
+{
+    archetypes: Vec<Archetype>,
+    ids: HashMap<Box<[ComponentTypeUUID]>, ArchetypeId>,
+}
+
+impl Archetypes
+{
+    pub fn new() -> Self
+    {
+        let empty = Archetype::new(EMPTY_ARCHETYPE, Box::new([]), &ComponentRegistry::new());
+
+        Self
+        {
+            ids: HashMap::from([(empty.component_type_uuids.clone(), EMPTY_ARCHETYPE)]),
+            archetypes: vec![empty],
+        }
+    }
+
+    pub fn get(&self, id: ArchetypeId) -> &Archetype
+    {
+        &self.archetypes[id]
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Archetype>
+    {
+        self.archetypes.iter()
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.archetypes.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.archetypes.is_empty()
+    }
+
+    fn get_or_create(&mut self, component_type_uuids: Vec<ComponentTypeUUID>, registry: &ComponentRegistry) -> ArchetypeId
+    {
+        if let Some(&id) = self.ids.get(component_type_uuids.as_slice())
+        {
+            return id;
+        }
+
+        let id = self.archetypes.len();
+        let component_type_uuids: Box<[ComponentTypeUUID]> = component_type_uuids.into();
+
+        self.ids.insert(component_type_uuids.clone(), id);
+        self.archetypes.push(Archetype::new(id, component_type_uuids, registry));
+
+        id
+    }
+
+    pub(super) fn with_component(&mut self, id: ArchetypeId, component_type_uuid: ComponentTypeUUID, registry: &ComponentRegistry) -> ArchetypeId
+    {
+        let archetype = &self.archetypes[id];
+        if archetype.contains(component_type_uuid)
+        {
+            return id;
+        }
+
+        if let Some(&target) = archetype.add_edges.get(&component_type_uuid)
+        {
+            return target;
+        }
+
+        let mut component_type_uuids = archetype.component_type_uuids.to_vec();
+        let position = component_type_uuids.binary_search(&component_type_uuid).unwrap_err();
+        component_type_uuids.insert(position, component_type_uuid);
+
+        let target = self.get_or_create(component_type_uuids, registry);
+        self.archetypes[id].add_edges.insert(component_type_uuid, target);
+        target
+    }
+
+    pub(super) fn without_component(&mut self, id: ArchetypeId, component_type_uuid: ComponentTypeUUID, registry: &ComponentRegistry) -> ArchetypeId
+    {
+        let archetype = &self.archetypes[id];
+        if !archetype.contains(component_type_uuid)
+        {
+            return id;
+        }
+
+        if let Some(&target) = archetype.remove_edges.get(&component_type_uuid)
+        {
+            return target;
+        }
+
+        let component_type_uuids = archetype.component_type_uuids.iter().copied().filter(|&uuid| uuid != component_type_uuid).collect();
+
+        let target = self.get_or_create(component_type_uuids, registry);
+        self.archetypes[id].remove_edges.insert(component_type_uuid, target);
+        target
+    }
+
+    pub(super) fn push_entity(&mut self, id: ArchetypeId, entity: EntityUUID) -> EntityLocation
+    {
+        let archetype = &mut self.archetypes[id];
+        archetype.entities.push(entity);
+
+        EntityLocation
+        {
+            archetype: id,
+            row: archetype.entities.len() - 1,
+        }
+    }
+
+    // Drops the table components of the row. Returns the entity that now occupies `location.row`, if any.
+    pub(super) fn remove_entity(&mut self, location: EntityLocation) -> Option<EntityUUID>
+    {
+        let archetype = &mut self.archetypes[location.archetype];
+
+        for column in archetype.columns.values_mut()
+        {
+            column.swap_remove(location.row);
+        }
+
+        archetype.swap_remove_entity(location.row)
+    }
+
+    // Moves the row to the end of archetype `target`. Components missing from `target` are dropped,
+    // and columns `target` has in addition are left one row short for the caller to push into.
+    // Returns the new location and the entity that now occupies `location.row`, if any.
+    pub(super) fn move_entity(&mut self, location: EntityLocation, target: ArchetypeId) -> (EntityLocation, Option<EntityUUID>)
+    {
+        assert_ne!(location.archetype, target);
+
+        let (source, destination) = if location.archetype < target
+        {
+            let (left, right) = self.archetypes.split_at_mut(target);
+            (&mut left[location.archetype], &mut right[0])
+        }
+        else
+        {
+            let (left, right) = self.archetypes.split_at_mut(location.archetype);
+            (&mut right[0], &mut left[target])
+        };
+
+        for (component_type_uuid, column) in source.columns.iter_mut()
+        {
+            match destination.columns.get_mut(component_type_uuid) {
+                Some(destination_column) => column.move_row(location.row, destination_column),
+                None => column.swap_remove(location.row),
+            }
+        }
+
+        let entity = source.entities[location.row];
+        destination.entities.push(entity);
+
+        let new_location = EntityLocation
+        {
+            archetype: target,
+            row: destination.entities.len() - 1,
+        };
+
+        (new_location, source.swap_remove_entity(location.row))
+    }
+
+    // Moves the value behind `value` into the row, as a new component if the column is one row short.
+    pub(super) unsafe fn write(&mut self, location: EntityLocation, component_type_uuid: ComponentTypeUUID, value: *const u8, tick: Tick)
+    {
+        let column = self.archetypes[location.archetype].columns.get_mut(&component_type_uuid).unwrap();
+
+        if column.len() == location.row
+        {
+            column.push(value, ComponentTicks::new(tick));
+        }
+        else
+        {
+            column.replace(location.row, value, tick);
+        }
+    }
+}
+
+impl Default for Archetypes
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
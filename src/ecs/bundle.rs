@@ -0,0 +1,55 @@
+use std::mem::ManuallyDrop;
+
+use super::{component::ComponentTypeUUID, entity::Entity, ECSStorage};
+
+pub trait Bundle: 'static
+{
+    fn register_components(storage: &mut ECSStorage, component_type_uuids: &mut Vec<ComponentTypeUUID>);
+
+    // `component_type_uuids` is the list filled by `register_components`, in the same order.
+    fn write_components(self, storage: &mut ECSStorage, entity: Entity, component_type_uuids: &[ComponentTypeUUID]);
+}
+
+macro_rules! impl_bundle
+{
+    ($($name:ident),*) =>
+    {
+        #[allow(non_snake_case, unused_variables, unused_mut)]
+        impl<$($name: 'static),*> Bundle for ($($name,)*)
+        {
+            fn register_components(storage: &mut ECSStorage, component_type_uuids: &mut Vec<ComponentTypeUUID>)
+            {
+                $( component_type_uuids.push(storage.register_component_type::<$name>()); )*
+            }
+
+            fn write_components(self, storage: &mut ECSStorage, entity: Entity, component_type_uuids: &[ComponentTypeUUID])
+            {
+                let ($($name,)*) = self;
+                $( let mut $name = ManuallyDrop::new($name); )*
+                let mut component_type_uuids = component_type_uuids.iter();
+                let components = [$( (*component_type_uuids.next().unwrap(), &mut *$name as *mut $name as *mut u8) ),*];
+
+                // Safety: the values are wrapped in `ManuallyDrop`, so moving them into the storage is fine.
+                unsafe { storage.write_components_raw(entity, &components) };
+            }
+        }
+    };
+}
+
+impl_bundle!();
+impl_bundle!(T1);
+impl_bundle!(T1, T2);
+impl_bundle!(T1, T2, T3);
+impl_bundle!(T1, T2, T3, T4);
+impl_bundle!(T1, T2, T3, T4, T5);
+impl_bundle!(T1, T2, T3, T4, T5, T6);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15);
+impl_bundle!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15, T16);
@@ -0,0 +1,109 @@
+use std::ops::{Deref, DerefMut};
+
+pub type Tick = u64;
+
+// The ticks a system runs with: changes newer than `last_change_tick` count as changed,
+// and writes are stamped with `change_tick`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemTicks
+{
+    pub last_change_tick: Tick,
+    pub change_tick: Tick,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ComponentTicks
+{
+    pub added: Tick,
+    pub changed: Tick,
+}
+
+impl ComponentTicks
+{
+    pub fn new(tick: Tick) -> Self
+    {
+        Self
+        {
+            added: tick,
+            changed: tick,
+        }
+    }
+
+    pub fn is_added(&self, last_change_tick: Tick) -> bool
+    {
+        self.added > last_change_tick
+    }
+
+    pub fn is_changed(&self, last_change_tick: Tick) -> bool
+    {
+        self.changed > last_change_tick
+    }
+}
+
+// Mutable access to a component that stamps its change tick whenever it is dereferenced mutably.
+pub struct Mut<'a, T>
+{
+    value: &'a mut T,
+    ticks: &'a mut ComponentTicks,
+    last_change_tick: Tick,
+    change_tick: Tick,
+}
+
+impl<'a, T> Mut<'a, T>
+{
+    pub fn new(value: &'a mut T, ticks: &'a mut ComponentTicks, last_change_tick: Tick, change_tick: Tick) -> Self
+    {
+        Self
+        {
+            value,
+            ticks,
+            last_change_tick,
+            change_tick,
+        }
+    }
+
+    pub fn is_added(&self) -> bool
+    {
+        self.ticks.is_added(self.last_change_tick)
+    }
+
+    pub fn is_changed(&self) -> bool
+    {
+        self.ticks.is_changed(self.last_change_tick)
+    }
+
+    pub fn set_changed(&mut self)
+    {
+        self.ticks.changed = self.change_tick;
+    }
+
+    pub fn bypass_change_detection(&mut self) -> &mut T
+    {
+        self.value
+    }
+
+    pub fn into_inner(self) -> &'a mut T
+    {
+        self.ticks.changed = self.change_tick;
+        self.value
+    }
+}
+
+impl<T> Deref for Mut<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target
+    {
+        self.value
+    }
+}
+
+impl<T> DerefMut for Mut<'_, T>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target
+    {
+        self.set_changed();
+        self.value
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+
+use super::{bundle::Bundle, entity::Entity, ECSStorage};
+
+pub type Command = Box<dyn FnOnce(&mut ECSStorage) + Send>;
+
+// A handle to the deferred command queue of an `ECSStorage`. Clones share the same queue,
+// so a system can keep recording while it holds a query over the storage.
+#[derive(Clone)]
+pub struct Commands
+{
+    queue: Arc<Mutex<Vec<Command>>>,
+    entity_uuid_counter: Arc<AtomicUsize>,
+}
+
+impl Commands
+{
+    pub(super) fn new(entity_uuid_counter: Arc<AtomicUsize>) -> Self
+    {
+        Self
+        {
+            queue: Arc::new(Mutex::new(Vec::new())),
+            entity_uuid_counter,
+        }
+    }
+
+    pub fn add<F>(&mut self, command: F) where F: FnOnce(&mut ECSStorage) + Send + 'static
+    {
+        self.queue.lock().unwrap().push(Box::new(command));
+    }
+
+    // The returned entity is reserved right away, so later commands in the same buffer can refer to it.
+    pub fn spawn_empty(&mut self) -> Entity
+    {
+        let entity = Entity::new(self.entity_uuid_counter.fetch_add(1, Ordering::Relaxed) + 1, 0);
+        self.add(move |storage| storage.spawn_reserved(entity));
+        entity
+    }
+
+    pub fn spawn<B>(&mut self, bundle: B) -> Entity where B: Bundle + Send
+    {
+        let entity = self.spawn_empty();
+        self.insert_bundle(entity, bundle);
+        entity
+    }
+
+    pub fn despawn(&mut self, entity: Entity)
+    {
+        self.add(move |storage| { storage.remove_entity(entity); });
+    }
+
+    pub fn insert<T>(&mut self, entity: Entity, component: T) where T: Send + 'static
+    {
+        self.add(move |storage| storage.insert_component(entity, component));
+    }
+
+    pub fn insert_bundle<B>(&mut self, entity: Entity, bundle: B) where B: Bundle + Send
+    {
+        self.add(move |storage| storage.insert_bundle(entity, bundle));
+    }
+
+    pub fn remove<T>(&mut self, entity: Entity) where T: 'static
+    {
+        self.add(move |storage| storage.remove_component::<T>(entity));
+    }
+
+    pub fn insert_resource<R>(&mut self, resource: R) where R: Send + 'static
+    {
+        self.add(move |storage| { storage.insert_resource(resource); });
+    }
+
+    pub fn remove_resource<R>(&mut self) where R: 'static
+    {
+        self.add(move |storage| { storage.remove_resource::<R>(); });
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.queue.lock().unwrap().is_empty()
+    }
+
+    pub(super) fn take(&self) -> Vec<Command>
+    {
+        std::mem::take(&mut *self.queue.lock().unwrap())
+    }
+}
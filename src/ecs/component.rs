@@ -1,3 +1,7 @@
+use super::{command::Commands, entity::Entity};
+
+pub mod registry;
+
 pub type ComponentUUID = usize;
 pub type ComponentTypeUUID = usize;
 
@@ -6,8 +10,49 @@ pub trait Component: 'static
     fn new() -> Self where Self: Sized;
 }
 
+impl<T> Component for T where T: Default + 'static
+{
+    fn new() -> Self
+    {
+        T::default()
+    }
+}
+
 pub struct EntityComponent<T> where T: Component
 {
     pub uuid: ComponentUUID,
     pub component: T,
-}
\ No newline at end of file
+}
+
+pub type ComponentHook = Box<dyn Fn(Entity, &mut Commands) + Send + Sync>;
+
+// Callbacks the storage runs for a component type. `on_add` fires when an entity gains the component,
+// `on_insert` on every write including overwrites, and `on_remove` when it loses it, despawns included.
+#[derive(Default)]
+pub struct ComponentHooks
+{
+    pub(super) on_add: Option<ComponentHook>,
+    pub(super) on_insert: Option<ComponentHook>,
+    pub(super) on_remove: Option<ComponentHook>,
+}
+
+impl ComponentHooks
+{
+    pub fn on_add<F>(&mut self, hook: F) -> &mut Self where F: Fn(Entity, &mut Commands) + Send + Sync + 'static
+    {
+        self.on_add = Some(Box::new(hook));
+        self
+    }
+
+    pub fn on_insert<F>(&mut self, hook: F) -> &mut Self where F: Fn(Entity, &mut Commands) + Send + Sync + 'static
+    {
+        self.on_insert = Some(Box::new(hook));
+        self
+    }
+
+    pub fn on_remove<F>(&mut self, hook: F) -> &mut Self where F: Fn(Entity, &mut Commands) + Send + Sync + 'static
+    {
+        self.on_remove = Some(Box::new(hook));
+        self
+    }
+}
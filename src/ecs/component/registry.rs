@@ -0,0 +1,392 @@
+use std::{alloc::Layout, any::{type_name, TypeId}, borrow::Cow, collections::HashMap, error::Error, fmt};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::data_structures::type_erased_vec::drop_erased;
+
+use super::{ComponentHooks, ComponentTypeUUID};
+
+pub type DropFn = unsafe fn(*mut u8);
+// Clones the value behind the first pointer into the uninitialized memory behind the second.
+pub type CloneFn = unsafe fn(*const u8, *mut u8);
+pub type SerializeFn = unsafe fn(*const u8) -> serde_json::Result<serde_json::Value>;
+// Writes the deserialized value into uninitialized memory; nothing is written on error.
+pub type DeserializeFn = unsafe fn(serde_json::Value, *mut u8) -> serde_json::Result<()>;
+
+unsafe fn clone_erased<T: Clone>(source: *const u8, destination: *mut u8)
+{
+    std::ptr::write(destination as *mut T, (*(source as *const T)).clone());
+}
+
+unsafe fn serialize_erased<T: Serialize>(source: *const u8) -> serde_json::Result<serde_json::Value>
+{
+    serde_json::to_value(&*(source as *const T))
+}
+
+unsafe fn deserialize_erased<T: DeserializeOwned>(value: serde_json::Value, destination: *mut u8) -> serde_json::Result<()>
+{
+    std::ptr::write(destination as *mut T, serde_json::from_value::<T>(value)?);
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind
+{
+    SparseSet,
+    Table,
+}
+
+pub struct ComponentInfo
+{
+    id: ComponentTypeUUID,
+    name: Cow<'static, str>,
+    type_id: Option<TypeId>,
+    layout: Layout,
+    drop_fn: Option<DropFn>,
+    clone_fn: Option<CloneFn>,
+    serialize_fn: Option<SerializeFn>,
+    deserialize_fn: Option<DeserializeFn>,
+    storage_kind: StorageKind,
+    pub(in crate::ecs) hooks: ComponentHooks,
+}
+
+impl ComponentInfo
+{
+    fn new<T: 'static>(id: ComponentTypeUUID) -> Self
+    {
+        Self
+        {
+            id,
+            name: Cow::Borrowed(type_name::<T>()),
+            type_id: Some(TypeId::of::<T>()),
+            layout: Layout::new::<T>(),
+            drop_fn: if std::mem::needs_drop::<T>() { Some(drop_erased::<T> as DropFn) } else { None },
+            clone_fn: None,
+            serialize_fn: None,
+            deserialize_fn: None,
+            storage_kind: StorageKind::SparseSet,
+            hooks: ComponentHooks::default(),
+        }
+    }
+
+    fn new_dynamic(id: ComponentTypeUUID, name: Cow<'static, str>, layout: Layout, drop_fn: Option<DropFn>) -> Self
+    {
+        Self
+        {
+            id,
+            name,
+            type_id: None,
+            layout,
+            drop_fn,
+            clone_fn: None,
+            serialize_fn: None,
+            deserialize_fn: None,
+            storage_kind: StorageKind::SparseSet,
+            hooks: ComponentHooks::default(),
+        }
+    }
+
+    pub fn id(&self) -> ComponentTypeUUID
+    {
+        self.id
+    }
+
+    pub fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    // `None` for components that have no Rust type behind them.
+    pub fn type_id(&self) -> Option<TypeId>
+    {
+        self.type_id
+    }
+
+    pub fn layout(&self) -> Layout
+    {
+        self.layout
+    }
+
+    pub fn drop_fn(&self) -> Option<DropFn>
+    {
+        self.drop_fn
+    }
+
+    pub fn clone_fn(&self) -> Option<CloneFn>
+    {
+        self.clone_fn
+    }
+
+    pub fn serialize_fn(&self) -> Option<SerializeFn>
+    {
+        self.serialize_fn
+    }
+
+    pub fn deserialize_fn(&self) -> Option<DeserializeFn>
+    {
+        self.deserialize_fn
+    }
+
+    pub fn storage_kind(&self) -> StorageKind
+    {
+        self.storage_kind
+    }
+
+    // Only `ECSStorage::set_storage_kind` may change it, since existing values would have to move.
+    pub(in crate::ecs) fn set_storage_kind(&mut self, storage_kind: StorageKind)
+    {
+        self.storage_kind = storage_kind;
+    }
+
+    pub fn hooks(&self) -> &ComponentHooks
+    {
+        &self.hooks
+    }
+
+    fn assert_type<T: 'static>(&self)
+    {
+        assert!(self.type_id == Some(TypeId::of::<T>()), "Component {} is not of type {}", self.name, type_name::<T>());
+    }
+
+    pub fn with_clone<T: Clone + 'static>(&mut self) -> &mut Self
+    {
+        self.assert_type::<T>();
+        self.clone_fn = Some(clone_erased::<T>);
+        self
+    }
+
+    pub fn with_serde<T: Serialize + DeserializeOwned + 'static>(&mut self) -> &mut Self
+    {
+        self.assert_type::<T>();
+        self.serialize_fn = Some(serialize_erased::<T>);
+        self.deserialize_fn = Some(deserialize_erased::<T>);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError
+{
+    DuplicateDynamicName(Cow<'static, str>),
+}
+
+impl fmt::Display for RegistryError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            RegistryError::DuplicateDynamicName(name) => write!(f, "A dynamic component named {} is already registered", name),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+// Ids are handed out from 1 and double as the bit index in an entity's component `BitSet`.
+// Typed components are found by their type path and dynamic ones by their name, in separate maps,
+// so a dynamic component named like a Rust type never collides with that type.
+pub struct ComponentRegistry
+{
+    infos: Vec<ComponentInfo>,
+    type_ids: HashMap<TypeId, ComponentTypeUUID>,
+    type_names: HashMap<Cow<'static, str>, ComponentTypeUUID>,
+    dynamic_names: HashMap<Cow<'static, str>, ComponentTypeUUID>,
+}
+
+impl ComponentRegistry
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            infos: Vec::new(),
+            type_ids: HashMap::new(),
+            type_names: HashMap::new(),
+            dynamic_names: HashMap::new(),
+        }
+    }
+
+    pub fn register<T: 'static>(&mut self) -> ComponentTypeUUID
+    {
+        if let Some(&id) = self.type_ids.get(&TypeId::of::<T>())
+        {
+            return id;
+        }
+
+        let id = self.infos.len() + 1;
+        let info = ComponentInfo::new::<T>(id);
+
+        self.type_ids.insert(TypeId::of::<T>(), id);
+        self.type_names.insert(info.name.clone(), id);
+        self.infos.push(info);
+
+        id
+    }
+
+    // Names must be unique among dynamic components, since they are the only way to find one again.
+    pub fn register_dynamic(&mut self, name: Cow<'static, str>, layout: Layout, drop_fn: Option<DropFn>) -> Result<ComponentTypeUUID, RegistryError>
+    {
+        if self.dynamic_names.contains_key(&name)
+        {
+            return Err(RegistryError::DuplicateDynamicName(name));
+        }
+
+        let id = self.infos.len() + 1;
+
+        self.dynamic_names.insert(name.clone(), id);
+        self.infos.push(ComponentInfo::new_dynamic(id, name, layout, drop_fn));
+
+        Ok(id)
+    }
+
+    pub fn id<T: 'static>(&self) -> Option<ComponentTypeUUID>
+    {
+        self.type_ids.get(&TypeId::of::<T>()).copied()
+    }
+
+    // Finds a typed component by its type path.
+    pub fn id_by_name(&self, name: &str) -> Option<ComponentTypeUUID>
+    {
+        self.type_names.get(name).copied()
+    }
+
+    pub fn dynamic_id(&self, name: &str) -> Option<ComponentTypeUUID>
+    {
+        self.dynamic_names.get(name).copied()
+    }
+
+    pub fn info(&self, id: ComponentTypeUUID) -> Option<&ComponentInfo>
+    {
+        id.checked_sub(1).and_then(|index| self.infos.get(index))
+    }
+
+    pub fn info_mut(&mut self, id: ComponentTypeUUID) -> Option<&mut ComponentInfo>
+    {
+        id.checked_sub(1).and_then(|index| self.infos.get_mut(index))
+    }
+
+    pub fn info_by_name(&self, name: &str) -> Option<&ComponentInfo>
+    {
+        self.id_by_name(name).and_then(|id| self.info(id))
+    }
+
+    pub fn dynamic_info(&self, name: &str) -> Option<&ComponentInfo>
+    {
+        self.dynamic_id(name).and_then(|id| self.info(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ComponentInfo>
+    {
+        self.infos.iter()
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.infos.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.infos.is_empty()
+    }
+}
+
+impl Default for ComponentRegistry
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::{alloc::Layout, any::{type_name, TypeId}};
+
+    use super::{ComponentRegistry, RegistryError, StorageKind};
+
+    #[test]
+    fn components_are_looked_up_by_type_and_name()
+    {
+        let mut registry = ComponentRegistry::new();
+        let id = registry.register::<u32>();
+        let string_id = registry.register::<String>();
+
+        assert_eq!(registry.register::<u32>(), id);
+        assert_ne!(string_id, id);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.id::<u32>(), Some(id));
+        assert_eq!(registry.id::<u64>(), None);
+        assert_eq!(registry.id_by_name(type_name::<String>()), Some(string_id));
+        assert_eq!(registry.info_by_name("missing").map(|info| info.id()), None);
+
+        let info = registry.info(id).unwrap();
+        assert_eq!(info.id(), id);
+        assert_eq!(info.name(), type_name::<u32>());
+        assert_eq!(info.type_id(), Some(TypeId::of::<u32>()));
+        assert_eq!(info.layout(), Layout::new::<u32>());
+        assert_eq!(info.storage_kind(), StorageKind::SparseSet);
+        assert!(info.drop_fn().is_none());
+        assert!(registry.info(string_id).unwrap().drop_fn().is_some());
+    }
+
+    #[test]
+    fn clone_and_serde_functions_are_opt_in()
+    {
+        let mut registry = ComponentRegistry::new();
+        let id = registry.register::<String>();
+        assert!(registry.info(id).unwrap().clone_fn().is_none());
+
+        let info = registry.info_mut(id).unwrap().with_clone::<String>().with_serde::<String>();
+        let source = String::from("value");
+        let mut destination = std::mem::MaybeUninit::<String>::uninit();
+
+        unsafe
+        {
+            info.clone_fn().unwrap()(&source as *const String as *const u8, destination.as_mut_ptr() as *mut u8);
+            assert_eq!(destination.assume_init(), "value");
+            assert_eq!(info.serialize_fn().unwrap()(&source as *const String as *const u8).unwrap(), "value");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is not of type")]
+    fn functions_of_another_type_are_rejected()
+    {
+        let mut registry = ComponentRegistry::new();
+        let id = registry.register::<u32>();
+        registry.info_mut(id).unwrap().with_clone::<u64>();
+    }
+
+    struct Position;
+
+    #[test]
+    fn typed_and_dynamic_names_do_not_collide()
+    {
+        let mut registry = ComponentRegistry::new();
+        let dynamic = registry.register_dynamic(type_name::<Position>().into(), Layout::new::<u64>(), None).unwrap();
+        let typed = registry.register::<Position>();
+
+        assert_ne!(typed, dynamic);
+        assert_eq!(registry.register::<Position>(), typed);
+        assert_eq!(registry.id_by_name(type_name::<Position>()), Some(typed));
+        assert_eq!(registry.dynamic_id(type_name::<Position>()), Some(dynamic));
+        assert_eq!(registry.dynamic_info(type_name::<Position>()).map(|info| info.layout()), Some(Layout::new::<u64>()));
+        assert_eq!(registry.dynamic_id("Health"), None);
+    }
+
+    #[test]
+    fn duplicate_dynamic_names_are_an_error()
+    {
+        let mut registry = ComponentRegistry::new();
+        let health = registry.register_dynamic("Health".into(), Layout::new::<u32>(), None);
+        assert_eq!(health, Ok(1));
+
+        let error = registry.register_dynamic("Health".into(), Layout::new::<u64>(), None).unwrap_err();
+        assert_eq!(error, RegistryError::DuplicateDynamicName("Health".into()));
+        assert_eq!(error.to_string(), "A dynamic component named Health is already registered");
+        assert_eq!(registry.len(), 1);
+    }
+}
@@ -1,3 +1,83 @@
-use std::num;
+use std::fmt;
 
-pub type EntityUUID = usize;
\ No newline at end of file
+use super::{bundle::Bundle, ECSStorage};
+
+pub type EntityUUID = usize;
+pub type EntityGeneration = u32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Entity
+{
+    index: EntityUUID,
+    generation: EntityGeneration,
+}
+
+impl Entity
+{
+    pub fn new(index: EntityUUID, generation: EntityGeneration) -> Self
+    {
+        Self
+        {
+            index,
+            generation,
+        }
+    }
+
+    pub fn index(&self) -> EntityUUID
+    {
+        self.index
+    }
+
+    pub fn generation(&self) -> EntityGeneration
+    {
+        self.generation
+    }
+}
+
+impl fmt::Display for Entity
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+pub struct EntityBuilder<'a>
+{
+    storage: &'a mut ECSStorage,
+    entity: Entity,
+}
+
+impl<'a> EntityBuilder<'a>
+{
+    pub fn new(storage: &'a mut ECSStorage, entity: Entity) -> Self
+    {
+        Self
+        {
+            storage,
+            entity,
+        }
+    }
+
+    pub fn with<T>(self, component: T) -> Self where T: 'static
+    {
+        self.storage.insert_component(self.entity, component);
+        self
+    }
+
+    pub fn with_bundle<B>(self, bundle: B) -> Self where B: Bundle
+    {
+        self.storage.insert_bundle(self.entity, bundle);
+        self
+    }
+
+    pub fn id(&self) -> Entity
+    {
+        self.entity
+    }
+
+    pub fn build(self) -> Entity
+    {
+        self.entity
+    }
+}
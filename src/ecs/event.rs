@@ -0,0 +1,112 @@
+use std::{any::Any, marker::PhantomData};
+
+// Double buffered event queue: events stay readable during the update they were sent in and the
+// one after, then `update` drops them.
+pub struct Events<E>
+{
+    previous: Vec<E>,
+    current: Vec<E>,
+    event_count: usize,
+}
+
+impl<E> Events<E>
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            previous: Vec::new(),
+            current: Vec::new(),
+            event_count: 0,
+        }
+    }
+
+    pub fn send(&mut self, event: E)
+    {
+        self.current.push(event);
+        self.event_count += 1;
+    }
+
+    pub fn update(&mut self)
+    {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.previous.clear();
+        self.current.clear();
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &E>
+    {
+        self.previous.iter().chain(self.current.iter())
+    }
+}
+
+impl<E> Default for Events<E>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+// Type-erased `Events::update`, registered by `ECSStorage::add_event`.
+pub(super) fn update_erased<E: 'static>(events: &mut dyn Any)
+{
+    if let Some(events) = events.downcast_mut::<Events<E>>()
+    {
+        events.update();
+    }
+}
+
+// Remembers how far it has read, so every event is seen at most once per cursor.
+pub struct EventCursor<E>
+{
+    last_event_count: usize,
+    _marker: PhantomData<fn() -> E>,
+}
+
+impl<E> EventCursor<E>
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            last_event_count: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn read<'a>(&mut self, events: &'a Events<E>) -> impl Iterator<Item = &'a E>
+    {
+        let current_start = events.event_count - events.current.len();
+        let previous_start = current_start - events.previous.len();
+
+        let previous_skip = self.last_event_count.saturating_sub(previous_start).min(events.previous.len());
+        let current_skip = self.last_event_count.saturating_sub(current_start).min(events.current.len());
+
+        self.last_event_count = events.event_count;
+
+        events.previous[previous_skip..].iter().chain(events.current[current_skip..].iter())
+    }
+}
+
+impl<E> Default for EventCursor<E>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
@@ -1,6 +1,59 @@
-use std::collections::HashMap;
+// Soundness of the query module
+//
+// A `QueryIter` only ever holds a shared `&'a ECSStorage`. Component data lives in `TypeErasedVec`
+// buffers that are reached through raw pointers, so writing through them does not conflict with
+// that shared borrow. Mutable access relies on three invariants:
+//
+// 1. Queries that are not read-only are only constructed from a `&'a mut ECSStorage`
+//    (`query_mut`, `query_filtered_mut`), so nothing else can touch the storage for `'a`.
+//    Read-only queries are gated by `ReadOnlyQueryTuple` and never write. The `Query` system
+//    parameter is the exception: it is built from a shared storage while its system runs with
+//    exclusive access, and `SystemAccess` rejects parameters of one system that would alias.
+// 2. The driving indices come from a single `SparseSet` or the rows of the archetypes, so each
+//    entity is visited at most once and no two items of one query refer to the same entity.
+// 3. `QueryTuple::validate_access` rejects tuples that access one component type mutably
+//    together with any other access to it, so the elements of a single item never alias.
+//
+// `dynamic::QueryBuilder` upholds the same invariants at runtime: write terms need `build_mut`,
+// and aliasing component ids are rejected when the query is built.
+
+use std::{any::{type_name, TypeId}, marker::PhantomData, ops::Range, ptr, slice};
+
+use crate::data_structures::sparse_set::{SparseSet, SparseSetIndices};
+
+use super::{archetype::{Archetype, ArchetypeId, Column}, change_detection::{ComponentTicks, Mut, SystemTicks, Tick}, component::{registry::StorageKind, ComponentTypeUUID}, entity::{Entity, EntityUUID}, ECSStorage};
+
+// Calls `$impl_macro!` for every tuple arity up to 16, so all tuple impls support the same sizes.
+// `@with_unit` adds the empty tuple, e.g. for the default `()` filter.
+macro_rules! all_tuples {
+    (@with_unit $impl_macro:ident) => {
+        $impl_macro!();
+        all_tuples!($impl_macro);
+    };
+    ($impl_macro:ident) => {
+        $impl_macro!(F1);
+        $impl_macro!(F1, F2);
+        $impl_macro!(F1, F2, F3);
+        $impl_macro!(F1, F2, F3, F4);
+        $impl_macro!(F1, F2, F3, F4, F5);
+        $impl_macro!(F1, F2, F3, F4, F5, F6);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15);
+        $impl_macro!(F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15, F16);
+    };
+}
+
+pub mod filter;
+pub mod dynamic;
 
-use super::ECSStorage;
+use filter::QueryFilter;
 
 pub trait ComponentQuery<'a> {
     type Iter: Iterator;
@@ -12,275 +65,813 @@ pub trait ComponentQueryMut<'a> {
     fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter;
 }
 
-impl<'a, T1: 'static> ComponentQuery<'a> for (&'a T1,) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1)> + 'a>;
+// Where the values of one component type live, resolved once per query.
+#[derive(Clone, Copy)]
+enum ComponentStorage<'a> {
+    SparseSet(&'a SparseSet<1000>),
+    // The column of the archetype last passed to `set_archetype`.
+    Table(Option<&'a Column>),
+}
 
-    fn query(storage: &'a ECSStorage) -> Self::Iter {
-        storage.iter_components::<T1>()
-    }
+// Looks up one component type for the entities a query visits, whichever storage it uses.
+#[derive(Clone, Copy)]
+pub struct ComponentFetch<'a> {
+    component_type_uuid: ComponentTypeUUID,
+    storage: ComponentStorage<'a>,
 }
 
-impl<'a, T1: 'static> ComponentQueryMut<'a> for (&'a mut T1,) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1)> + 'a>;
+impl<'a> ComponentFetch<'a> {
+    // `None` if the component type was never registered.
+    pub fn new(storage: &'a ECSStorage, component_type_uuid: ComponentTypeUUID) -> Option<Self> {
+        let component_storage = match storage.component_registry.info(component_type_uuid)?.storage_kind() {
+            StorageKind::SparseSet => ComponentStorage::SparseSet(storage.components.get(&component_type_uuid)?),
+            StorageKind::Table => ComponentStorage::Table(None),
+        };
+
+        Some(Self {
+            component_type_uuid,
+            storage: component_storage,
+        })
+    }
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        storage.iter_components_mut::<T1>()
+    pub fn of<T: 'static>(storage: &'a ECSStorage) -> Option<Self> {
+        Self::new(storage, storage.component_type_uuid::<T>()?)
     }
-}
 
-impl<'a, T1: 'static, T2: 'static> ComponentQuery<'a> for (&'a T1, &'a T2) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a T2)> + 'a>;
+    pub fn component_type_uuid(&self) -> ComponentTypeUUID {
+        self.component_type_uuid
+    }
 
-    fn query(storage: &'a ECSStorage) -> Self::Iter {
-        let iter1 = storage.iter_components::<T1>().collect::<HashMap<_, _>>();
-        let iter2 = storage.iter_components::<T2>();
+    // Only sparse sets can drive a query, table components are found through the archetypes.
+    pub fn driver(&self) -> Option<&'a SparseSet<1000>> {
+        match self.storage {
+            ComponentStorage::SparseSet(components) => Some(components),
+            ComponentStorage::Table(_) => None,
+        }
+    }
 
-        Box::new(iter2.filter_map(move |(i2, t2)| {
-            iter1.get(&i2).map(|t1| (i2, *t1, t2))
-        }))
+    pub fn matches_archetype(&self, archetype: &Archetype) -> bool {
+        match self.storage {
+            ComponentStorage::SparseSet(_) => true,
+            ComponentStorage::Table(_) => archetype.contains(self.component_type_uuid),
+        }
+    }
+
+    pub fn set_archetype(&mut self, archetype: &'a Archetype) {
+        if let ComponentStorage::Table(column) = &mut self.storage {
+            *column = archetype.column(self.component_type_uuid);
+        }
+    }
+
+    // `row` is the entity's row in the archetype last passed to `set_archetype`.
+    pub fn get(&self, entity: Entity, row: usize) -> Option<(*mut u8, *mut ComponentTicks)> {
+        match self.storage {
+            ComponentStorage::SparseSet(components) => components.get_with_ticks_ptr::<u8>(entity.index()),
+            ComponentStorage::Table(column) => column.map(|column| (column.get_ptr(row), column.get_ticks_ptr(row))),
+        }
     }
 }
-impl<'a, T1: 'static, T2: 'static> ComponentQueryMut<'a> for (&'a mut T1, &'a T2) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1, &'a T2)> + 'a>;
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *mut T1> = storage.iter_components_mut::<T1>()
-            .map(|(i, t1)| (i, t1 as *mut T1))
-            .collect();
+// A single element of a query tuple: `&T`, `&mut T`, `Option<&T>`, `Option<&mut T>` or `Entity`.
+// Mutable elements are handed out as `Mut<T>`, which records changes for `Changed<T>`.
+pub trait QueryFetch<'a> {
+    type Item;
+    type State: Copy + 'a;
 
-        let iter2: Vec<(usize, *mut T2)> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+    // `None` means the query cannot match anything, e.g. a required component was never registered.
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Option<Self::State>;
 
-        Box::new(iter2.into_iter().filter_map(move |(i2, t2)| {
-            iter1.get(&i2).map(|&t1| (i2, unsafe { &mut *t1 }, unsafe { &*t2 }))
-        }))
-    }
+    // Required sparse set components can drive the iteration, optional ones cannot.
+    fn driver(state: &Self::State) -> Option<&'a SparseSet<1000>>;
+
+    // Lets queries without a driver skip whole archetypes.
+    fn matches_archetype(state: &Self::State, archetype: &Archetype) -> bool;
+
+    // Called before fetching from entities of another archetype than the previous one.
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype);
+
+    // The component this element reads or writes, if any.
+    fn access() -> Option<ComponentAccess>;
+
+    // Returns `None` if the entity does not match this element. `row` is the entity's row in the
+    // archetype last passed to `set_archetype`.
+    // Safety: for mutable elements, every entity must be fetched at most once while the items are alive,
+    // and the caller must have exclusive access to the storage `state` was created from.
+    unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item>;
 }
 
-impl <'a, T1: 'static, T2: 'static> ComponentQueryMut<'a> for (&'a T1, &'a mut T2) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a mut T2)> + 'a>;
+#[derive(Clone, Copy)]
+pub struct ComponentAccess {
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    pub mutable: bool,
+}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *const T1> = storage.iter_components::<T1>()
-            .map(|(i, t1)| (i, t1 as *const T1))
-            .collect();
+impl ComponentAccess {
+    pub fn of<T: 'static>(mutable: bool) -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+            mutable,
+        }
+    }
 
-        let iter2: Vec<(usize, *mut T2)> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+    pub fn conflicts_with(&self, other: &ComponentAccess) -> bool {
+        self.type_id == other.type_id && (self.mutable || other.mutable)
+    }
+}
 
-        Box::new(iter2.into_iter().filter_map(move |(i2, t2)| {
-            iter1.get(&i2).map(|&t1| (i2, unsafe { &*t1 }, unsafe { &mut *t2 }))
-        }))
+// Panics if two accesses in the set alias and at least one of them is mutable.
+pub fn validate_access(accesses: &[Option<ComponentAccess>]) {
+    for (i, access) in accesses.iter().enumerate() {
+        let Some(access) = access else { continue };
+        for other in accesses[i + 1..].iter().flatten() {
+            if access.conflicts_with(other) {
+                panic!("Query accesses {} mutably more than once or both mutably and immutably", access.type_name);
+            }
+        }
     }
 }
 
-impl <'a, T1: 'static, T2: 'static> ComponentQueryMut<'a> for (&'a mut T1, &'a mut T2) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1, &'a mut T2)> + 'a>;
+// Marker for elements that never hand out mutable access, usable from `ComponentQuery`.
+pub trait ReadOnlyQueryFetch<'a>: QueryFetch<'a> {}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *mut T1> = storage.iter_components_mut::<T1>()
-            .map(|(i, t1)| (i, t1 as *mut T1))
-            .collect();
+impl<'a, T: 'static> QueryFetch<'a> for &'a T {
+    type Item = &'a T;
+    type State = ComponentFetch<'a>;
+
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess::of::<T>(false))
+    }
+
+    fn init(storage: &'a ECSStorage, _: SystemTicks) -> Option<Self::State> {
+        ComponentFetch::of::<T>(storage)
+    }
 
-        let iter2: Vec<(usize, *mut T2)> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+    fn driver(state: &Self::State) -> Option<&'a SparseSet<1000>> {
+        state.driver()
+    }
+
+    fn matches_archetype(state: &Self::State, archetype: &Archetype) -> bool {
+        state.matches_archetype(archetype)
+    }
+
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype) {
+        state.set_archetype(archetype);
+    }
 
-        Box::new(iter2.into_iter().filter_map(move |(i2, t2)| {
-            iter1.get(&i2).map(|&t1| (i2, unsafe { &mut *t1 }, unsafe { &mut *t2 }))
-        }))
+    unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item> {
+        state.get(entity, row).map(|(component, _)| &*(component as *const T))
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQuery<'a> for (&'a T1, &'a T2, &'a T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a T2, &'a T3)> + 'a>;
+impl<'a, T: 'static> ReadOnlyQueryFetch<'a> for &'a T {}
 
-    fn query(storage: &'a ECSStorage) -> Self::Iter {
-        let iter1 = storage.iter_components::<T1>().collect::<HashMap<_, _>>();
-        let iter2 = storage.iter_components::<T2>().collect::<HashMap<_, _>>();
-        let iter3 = storage.iter_components::<T3>();
+impl<'a, T: 'static> QueryFetch<'a> for &'a mut T {
+    type Item = Mut<'a, T>;
+    type State = (ComponentFetch<'a>, Tick, Tick);
+
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess::of::<T>(true))
+    }
+
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Option<Self::State> {
+        Some((ComponentFetch::of::<T>(storage)?, ticks.last_change_tick, ticks.change_tick))
+    }
 
-        Box::new(iter3.filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|t1| iter2.get(&i3).map(|t2| (i3, *t1, *t2, t3)))
-        }))
+    fn driver(state: &Self::State) -> Option<&'a SparseSet<1000>> {
+        state.0.driver()
+    }
+
+    fn matches_archetype(state: &Self::State, archetype: &Archetype) -> bool {
+        state.0.matches_archetype(archetype)
+    }
+
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype) {
+        state.0.set_archetype(archetype);
+    }
+
+    unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item> {
+        let (components, last_change_tick, change_tick) = *state;
+        // Safety: both pointers come from the live buffers of `T`, and the caller guarantees
+        // this entity is not fetched again and no one else accesses the storage for `'a`.
+        components.get(entity, row).map(|(component, ticks)| Mut::new(&mut *(component as *mut T), &mut *ticks, last_change_tick, change_tick))
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a T1, &'a T2, &'a T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a T2, &'a T3)> + 'a>;
+impl<'a, T: 'static> QueryFetch<'a> for Option<&'a T> {
+    type Item = Option<&'a T>;
+    type State = Option<ComponentFetch<'a>>;
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *const T1> = storage.iter_components::<T1>()
-            .map(|(i, t1)| (i, t1 as *const T1))
-            .collect();
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess::of::<T>(false))
+    }
+
+    fn init(storage: &'a ECSStorage, _: SystemTicks) -> Option<Self::State> {
+        Some(ComponentFetch::of::<T>(storage))
+    }
 
-        let iter2: HashMap<usize, *const T2> = storage.iter_components::<T2>()
-            .map(|(i, t2)| (i, t2 as *const T2))
-            .collect();
+    fn driver(_: &Self::State) -> Option<&'a SparseSet<1000>> {
+        None
+    }
 
-        let iter3: Vec<(usize, *const T3)> = storage.iter_components::<T3>()
-            .map(|(i, t3)| (i, t3 as *const T3))
-            .collect();
+    fn matches_archetype(_: &Self::State, _: &Archetype) -> bool {
+        true
+    }
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &*t1 }, unsafe { &*t2 }, unsafe { &*t3 })))
-        }))
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype) {
+        if let Some(components) = state {
+            components.set_archetype(archetype);
+        }
+    }
+
+    unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item> {
+        Some(state.and_then(|components| components.get(entity, row)).map(|(component, _)| &*(component as *const T)))
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a mut T1, &'a T2, &'a T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1, &'a T2, &'a T3)> + 'a>;
+impl<'a, T: 'static> ReadOnlyQueryFetch<'a> for Option<&'a T> {}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *mut T1> = storage.iter_components_mut::<T1>()
-            .map(|(i, t1)| (i, t1 as *mut T1))
-            .collect();
+impl<'a, T: 'static> QueryFetch<'a> for Option<&'a mut T> {
+    type Item = Option<Mut<'a, T>>;
+    type State = (Option<ComponentFetch<'a>>, Tick, Tick);
+
+    fn access() -> Option<ComponentAccess> {
+        Some(ComponentAccess::of::<T>(true))
+    }
+
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Option<Self::State> {
+        Some((ComponentFetch::of::<T>(storage), ticks.last_change_tick, ticks.change_tick))
+    }
+
+    fn driver(_: &Self::State) -> Option<&'a SparseSet<1000>> {
+        None
+    }
 
-        let iter2: HashMap<usize, *const T2> = storage.iter_components::<T2>()
-            .map(|(i, t2)| (i, t2 as *const T2))
-            .collect();
+    fn matches_archetype(_: &Self::State, _: &Archetype) -> bool {
+        true
+    }
 
-        let iter3: Vec<(usize, *mut T3)> = storage.iter_components_mut::<T3>()
-            .map(|(i, t3)| (i, t3 as *mut T3))
-            .collect();
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype) {
+        if let Some(components) = &mut state.0 {
+            components.set_archetype(archetype);
+        }
+    }
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &mut *t1 }, unsafe { &*t2 }, unsafe { &*t3 })))
-        }))
+    unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item> {
+        let (components, last_change_tick, change_tick) = *state;
+        // Safety: same as for `&mut T`.
+        Some(components.and_then(|components| components.get(entity, row)).map(|(component, ticks)| Mut::new(&mut *(component as *mut T), &mut *ticks, last_change_tick, change_tick)))
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a T1, &'a mut T2, &'a T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a mut T2, &'a T3)> + 'a>;
+impl<'a> QueryFetch<'a> for Entity {
+    type Item = Entity;
+    type State = ();
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *const T1> = storage.iter_components::<T1>()
-            .map(|(i, t1)| (i, t1 as *const T1))
-            .collect();
+    fn access() -> Option<ComponentAccess> {
+        None
+    }
 
-        let iter2: HashMap<usize, *mut T2> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+    fn init(_: &'a ECSStorage, _: SystemTicks) -> Option<Self::State> {
+        Some(())
+    }
+
+    fn driver(_: &Self::State) -> Option<&'a SparseSet<1000>> {
+        None
+    }
+
+    fn matches_archetype(_: &Self::State, _: &Archetype) -> bool {
+        true
+    }
 
-        let iter3: Vec<(usize, *mut T3)> = storage.iter_components_mut::<T3>()
-            .map(|(i, t3)| (i, t3 as *mut T3))
-            .collect();
+    fn set_archetype(_: &mut Self::State, _: &'a Archetype) {}
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &*t1 }, unsafe { &mut *t2 }, unsafe { &*t3 })))
-        }))
+    unsafe fn fetch(_: &Self::State, entity: Entity, _: usize) -> Option<Self::Item> {
+        Some(entity)
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a mut T1, &'a mut T2, &'a T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1, &'a mut T2, &'a T3)> + 'a>;
+impl ReadOnlyQueryFetch<'_> for Entity {}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *mut T1> = storage.iter_components_mut::<T1>()
-            .map(|(i, t1)| (i, t1 as *mut T1))
-            .collect();
+// A tuple of `QueryFetch` elements. Its items are the fetched elements prefixed with the entity.
+pub trait QueryTuple<'a> {
+    type Item;
+    type State: Copy + 'a;
+
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Option<Self::State>;
+
+    // The smallest set among the required elements, so the fewest candidates have to be probed.
+    fn driver(state: &Self::State) -> Option<&'a SparseSet<1000>>;
+
+    fn matches_archetype(state: &Self::State, archetype: &Archetype) -> bool;
+
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype);
 
-        let iter2: HashMap<usize, *mut T2> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+    // Panics if the elements alias, see `validate_access`.
+    fn validate_access();
 
-        let iter3: Vec<(usize, *const T3)> = storage.iter_components::<T3>()
-            .map(|(i, t3)| (i, t3 as *const T3))
-            .collect();
+    // Safety: see `QueryFetch::fetch`; in addition `validate_access` must have passed.
+    unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item>;
+}
+
+pub trait ReadOnlyQueryTuple<'a>: QueryTuple<'a> {}
+
+// A query tuple named without knowing the storage borrow, as in `Query<(&mut Pos, &Vel)>`.
+// Lifetimes written in the tuple are ignored, items borrow for as long as the query does.
+// Such queries may run on other threads, hence the `Sync` and `Send` bounds of the elements.
+pub trait QueryData {
+    type Fetch<'a>: QueryTuple<'a>;
+
+    fn access() -> Vec<ComponentAccess>;
+}
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &mut *t1 }, unsafe { &mut *t2 }, unsafe { &*t3 })))
-        }))
+pub trait ReadOnlyQueryData: QueryData {}
+
+pub trait QueryDataElement {
+    type Fetch<'a>: QueryFetch<'a>;
+}
+
+pub trait ReadOnlyQueryDataElement: QueryDataElement {}
+
+impl<T: Sync + 'static> QueryDataElement for &T {
+    type Fetch<'a> = &'a T;
+}
+
+impl<T: Sync + 'static> ReadOnlyQueryDataElement for &T {}
+
+impl<T: Send + 'static> QueryDataElement for &mut T {
+    type Fetch<'a> = &'a mut T;
+}
+
+impl<T: Sync + 'static> QueryDataElement for Option<&T> {
+    type Fetch<'a> = Option<&'a T>;
+}
+
+impl<T: Sync + 'static> ReadOnlyQueryDataElement for Option<&T> {}
+
+impl<T: Send + 'static> QueryDataElement for Option<&mut T> {
+    type Fetch<'a> = Option<&'a mut T>;
+}
+
+impl QueryDataElement for Entity {
+    type Fetch<'a> = Entity;
+}
+
+impl ReadOnlyQueryDataElement for Entity {}
+
+fn smallest<'a>(driver: Option<&'a SparseSet<1000>>, other: Option<&'a SparseSet<1000>>) -> Option<&'a SparseSet<1000>> {
+    match (driver, other) {
+        (Some(driver), Some(other)) if other.len() < driver.len() => Some(other),
+        (None, other) => other,
+        (driver, _) => driver,
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a T1, &'a T2, &'a mut T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a T2, &'a mut T3)> + 'a>;
+macro_rules! impl_query_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<'a, $($name: QueryFetch<'a>),*> QueryTuple<'a> for ($($name,)*) {
+            type Item = (Entity, $($name::Item),*);
+            type State = ($($name::State,)*);
+
+            fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Option<Self::State> {
+                Some(($($name::init(storage, ticks)?,)*))
+            }
+
+            fn driver(state: &Self::State) -> Option<&'a SparseSet<1000>> {
+                let ($($name,)*) = state;
+                let driver = None;
+                $( let driver = smallest(driver, $name::driver($name)); )*
+                driver
+            }
+
+            fn matches_archetype(state: &Self::State, archetype: &Archetype) -> bool {
+                let ($($name,)*) = state;
+                true $(&& $name::matches_archetype($name, archetype))*
+            }
+
+            fn set_archetype(state: &mut Self::State, archetype: &'a Archetype) {
+                let ($($name,)*) = state;
+                $( $name::set_archetype($name, archetype); )*
+            }
+
+            fn validate_access() {
+                validate_access(&[$($name::access()),*]);
+            }
+
+            unsafe fn fetch(state: &Self::State, entity: Entity, row: usize) -> Option<Self::Item> {
+                let ($($name,)*) = state;
+                Some((entity, $($name::fetch($name, entity, row)?),*))
+            }
+        }
+
+        impl<'a, $($name: ReadOnlyQueryFetch<'a>),*> ReadOnlyQueryTuple<'a> for ($($name,)*) {}
+
+        impl<$($name: QueryDataElement),*> QueryData for ($($name,)*) {
+            type Fetch<'a> = ($($name::Fetch<'a>,)*);
+
+            fn access() -> Vec<ComponentAccess> {
+                [$(<$name::Fetch<'static> as QueryFetch<'static>>::access()),*].into_iter().flatten().collect()
+            }
+        }
+
+        impl<$($name: ReadOnlyQueryDataElement),*> ReadOnlyQueryData for ($($name,)*) {}
+    };
+}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *const T1> = storage.iter_components::<T1>()
-            .map(|(i, t1)| (i, t1 as *const T1))
-            .collect();
+all_tuples!(impl_query_tuple);
+
+// Candidate entities and their archetype rows: the driving component set, or the rows of every
+// matching archetype if no required element is stored in a sparse set.
+enum QueryIndices<'a> {
+    Empty,
+    Components(SparseSetIndices<'a, 1000>),
+    Archetypes {
+        archetypes: slice::Iter<'a, Archetype>,
+        current: Option<&'a Archetype>,
+        row: usize,
+    },
+}
 
-        let iter2: HashMap<usize, *const T2> = storage.iter_components::<T2>()
-            .map(|(i, t2)| (i, t2 as *const T2))
-            .collect();
+impl<'a> QueryIndices<'a> {
+    fn new(storage: &'a ECSStorage, driver: Option<Option<&'a SparseSet<1000>>>) -> Self {
+        match driver {
+            None => QueryIndices::Empty,
+            Some(Some(components)) => QueryIndices::Components(components.indices()),
+            Some(None) => QueryIndices::Archetypes {
+                archetypes: storage.archetypes.iter(),
+                current: None,
+                row: 0,
+            },
+        }
+    }
 
-        let iter3: Vec<(usize, *mut T3)> = storage.iter_components_mut::<T3>()
-            .map(|(i, t3)| (i, t3 as *mut T3))
-            .collect();
+    // `matches` is only consulted when walking archetypes.
+    fn next(&mut self, storage: &'a ECSStorage, matches: impl Fn(&Archetype) -> bool) -> Option<(EntityUUID, &'a Archetype, usize)> {
+        match self {
+            QueryIndices::Empty => None,
+            QueryIndices::Components(indices) => {
+                let index = indices.next()?;
+                let location = storage.entity_locations[index];
+                Some((index, storage.archetypes.get(location.archetype), location.row))
+            }
+            QueryIndices::Archetypes { archetypes, current, row } => loop {
+                if let Some(archetype) = *current {
+                    if *row < archetype.len() {
+                        *row += 1;
+                        return Some((archetype.entities()[*row - 1], archetype, *row - 1));
+                    }
+                }
+
+                *current = Some(archetypes.find(|archetype| !archetype.is_empty() && matches(archetype))?);
+                *row = 0;
+            },
+        }
+    }
+}
+
+// The element and filter states of a query, pointed at the archetype of the last candidate.
+struct QueryCursor<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> {
+    state: Q::State,
+    filter: F::State,
+    archetype: Option<&'a Archetype>,
+}
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &*t1 }, unsafe { &*t2 }, unsafe { &mut *t3 })))
-        }))
+impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> Clone for QueryCursor<'a, Q, F> {
+    fn clone(&self) -> Self {
+        *self
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a mut T1, &'a T2, &'a mut T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1, &'a T2, &'a mut T3)> + 'a>;
+impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> Copy for QueryCursor<'a, Q, F> {}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *mut T1> = storage.iter_components_mut::<T1>()
-            .map(|(i, t1)| (i, t1 as *mut T1))
-            .collect();
+impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> QueryCursor<'a, Q, F> {
+    // Safety: see `QueryTuple::fetch`.
+    unsafe fn probe(&mut self, storage: &'a ECSStorage, index: EntityUUID, archetype: &'a Archetype, row: usize) -> Option<Q::Item> {
+        if !self.archetype.is_some_and(|current| ptr::eq(current, archetype)) {
+            Q::set_archetype(&mut self.state, archetype);
+            F::set_archetype(&mut self.filter, archetype);
+            self.archetype = Some(archetype);
+        }
 
-        let iter2: HashMap<usize, *const T2> = storage.iter_components::<T2>()
-            .map(|(i, t2)| (i, t2 as *const T2))
-            .collect();
+        let entity = storage.entity_at_index(index);
 
-        let iter3: Vec<(usize, *mut T3)> = storage.iter_components_mut::<T3>()
-            .map(|(i, t3)| (i, t3 as *mut T3))
-            .collect();
+        if !F::UNFILTERED {
+            let bit_set = &storage.entity_components_bitset[&index];
+            if !F::matches(&self.filter, entity, bit_set, row) {
+                return None;
+            }
+        }
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &mut *t1 }, unsafe { &*t2 }, unsafe { &mut *t3 })))
-        }))
+        Q::fetch(&self.state, entity, row)
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a T1, &'a mut T2, &'a mut T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a T1, &'a mut T2, &'a mut T3)> + 'a>;
+// A run of candidates for one worker of `par_for_each`.
+enum QueryBatch<'a> {
+    Components(SparseSetIndices<'a, 1000>),
+    Rows(ArchetypeId, Range<usize>),
+}
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *const T1> = storage.iter_components::<T1>()
-            .map(|(i, t1)| (i, t1 as *const T1))
-            .collect();
+// Lets the workers of `par_for_each` share the storage and copy the cursor. The cursor only
+// holds shared references into the storage, and every entity belongs to a single batch, so no
+// two workers fetch the same component.
+struct ParallelQuery<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> {
+    storage: &'a ECSStorage,
+    cursor: QueryCursor<'a, Q, F>,
+}
+
+unsafe impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> Sync for ParallelQuery<'a, Q, F> {}
 
-        let iter2: HashMap<usize, *mut T2> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> ParallelQuery<'a, Q, F> {
+    // Closures capturing the fields directly would not be `Sync`.
+    fn get(&self) -> (&'a ECSStorage, QueryCursor<'a, Q, F>) {
+        (self.storage, self.cursor)
+    }
+}
+
+// Walks the driving set or the matching archetypes and probes every element through its sparse
+// pages or archetype columns, so a query call does not allocate.
+pub struct QueryIter<'a, Q: QueryTuple<'a>, F: QueryFilter<'a> = ()> {
+    storage: &'a ECSStorage,
+    // `None` if the query cannot match anything.
+    cursor: Option<QueryCursor<'a, Q, F>>,
+    indices: QueryIndices<'a>,
+    _marker: PhantomData<(Q, F)>,
+}
+
+impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> QueryIter<'a, Q, F> {
+    // Safety: if `Q` is not read-only, the caller must hold exclusive access to `storage` for `'a`.
+    pub(super) unsafe fn new(storage: &'a ECSStorage) -> Self {
+        Self::with_ticks(storage, storage.system_ticks())
+    }
 
-        let iter3: Vec<(usize, *mut T3)> = storage.iter_components_mut::<T3>()
-            .map(|(i, t3)| (i, t3 as *mut T3))
-            .collect();
+    // Like `new`, for systems that do not run with the ticks stored in `storage`.
+    // Safety: if `Q` is not read-only, the caller must hold exclusive access to the components `Q` writes for `'a`.
+    pub(super) unsafe fn with_ticks(storage: &'a ECSStorage, ticks: SystemTicks) -> Self {
+        Q::validate_access();
+
+        let state = Q::init(storage, ticks);
+        let indices = QueryIndices::new(storage, state.as_ref().map(Q::driver));
+
+        Self {
+            storage,
+            cursor: state.map(|state| QueryCursor {
+                state,
+                filter: F::init(storage, ticks),
+                archetype: None,
+            }),
+            indices,
+            _marker: PhantomData,
+        }
+    }
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &*t1 }, unsafe { &mut *t2 }, unsafe { &mut *t3 })))
-        }))
+    // Calls `f` with every remaining item, in batches of up to `batch_size` entities spread over
+    // the threads of the storage's `TaskPool`. Items are created on the worker threads, hence `Send`.
+    pub fn par_for_each(self, batch_size: usize, f: impl Fn(Q::Item) + Sync) where Q::Item: Send {
+        assert!(batch_size > 0, "Batch size must be positive");
+
+        let Some(cursor) = self.cursor else { return };
+
+        let batches: Vec<QueryBatch<'a>> = match self.indices {
+            QueryIndices::Empty => Vec::new(),
+            QueryIndices::Components(indices) => indices.chunks(batch_size).map(QueryBatch::Components).collect(),
+            QueryIndices::Archetypes { archetypes, current, row } => {
+                let current = current.map(|archetype| (archetype, row));
+                let remaining = archetypes.filter(|archetype| !archetype.is_empty() && Q::matches_archetype(&cursor.state, archetype)).map(|archetype| (archetype, 0));
+
+                current.into_iter().chain(remaining).flat_map(|(archetype, start)| {
+                    (start..archetype.len()).step_by(batch_size).map(move |row| QueryBatch::Rows(archetype.id(), row..(row + batch_size).min(archetype.len())))
+                }).collect()
+            }
+        };
+
+        let query = ParallelQuery { storage: self.storage, cursor };
+
+        self.storage.task_pool().for_each(&batches, |batch| {
+            let (storage, mut cursor) = query.get();
+
+            // Safety: the iterator was created with the required access, and each entity is in one batch only.
+            match batch {
+                QueryBatch::Components(indices) => for index in indices.clone() {
+                    let location = storage.entity_locations[index];
+                    if let Some(item) = unsafe { cursor.probe(storage, index, storage.archetypes.get(location.archetype), location.row) } {
+                        f(item);
+                    }
+                },
+                QueryBatch::Rows(archetype, rows) => {
+                    let archetype = storage.archetypes.get(*archetype);
+                    for row in rows.clone() {
+                        if let Some(item) = unsafe { cursor.probe(storage, archetype.entities()[row], archetype, row) } {
+                            f(item);
+                        }
+                    }
+                }
+            }
+        });
     }
 }
 
-impl <'a, T1: 'static, T2: 'static, T3: 'static> ComponentQueryMut<'a> for (&'a mut T1, &'a mut T2, &'a mut T3) {
-    type Iter = Box<dyn Iterator<Item = (usize, &'a mut T1, &'a mut T2, &'a mut T3)> + 'a>;
+impl<'a, Q: QueryTuple<'a>, F: QueryFilter<'a>> Iterator for QueryIter<'a, Q, F> {
+    type Item = Q::Item;
 
-    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
-        let iter1: HashMap<usize, *mut T1> = storage.iter_components_mut::<T1>()
-            .map(|(i, t1)| (i, t1 as *mut T1))
-            .collect();
+    fn next(&mut self) -> Option<Self::Item> {
+        let cursor = self.cursor.as_mut()?;
+
+        while let Some((index, archetype, row)) = self.indices.next(self.storage, |archetype| Q::matches_archetype(&cursor.state, archetype)) {
+            // Safety: `new` validated the access set and its caller vouched for exclusivity,
+            // and `indices` yields every entity at most once.
+            if let Some(item) = unsafe { cursor.probe(self.storage, index, archetype, row) } {
+                return Some(item);
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a, Q: QueryTuple<'a>> ComponentQueryMut<'a> for Q {
+    type Iter = QueryIter<'a, Q>;
 
-        let iter2: HashMap<usize, *mut T2> = storage.iter_components_mut::<T2>()
-            .map(|(i, t2)| (i, t2 as *mut T2))
-            .collect();
+    fn query_mut(storage: &'a mut ECSStorage) -> Self::Iter {
+        // Safety: the storage is mutably borrowed for `'a`.
+        unsafe { QueryIter::new(storage) }
+    }
+}
 
-        let iter3: Vec<(usize, *mut T3)> = storage.iter_components_mut::<T3>()
-            .map(|(i, t3)| (i, t3 as *mut T3))
-            .collect();
+impl<'a, Q: ReadOnlyQueryTuple<'a>> ComponentQuery<'a> for Q {
+    type Iter = QueryIter<'a, Q>;
 
-        Box::new(iter3.into_iter().filter_map(move |(i3, t3)| {
-            iter1.get(&i3).and_then(|&t1| iter2.get(&i3).map(|&t2| (i3, unsafe { &mut *t1 }, unsafe { &mut *t2 }, unsafe { &mut *t3 })))
-        }))
+    fn query(storage: &'a ECSStorage) -> Self::Iter {
+        // Safety: `Q` is read-only, so a shared borrow is enough.
+        unsafe { QueryIter::new(storage) }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::ecs::{component::registry::StorageKind, entity::Entity, query::filter::Without, task_pool::TaskPool, ECSStorage};
+
+    fn storage() -> ECSStorage {
+        let mut storage = ECSStorage::new();
+        storage.spawn((1u32, 10u64));
+        storage.spawn((2u32,));
+        storage
+    }
+
+    #[test]
+    fn optional_elements_fetch_present_and_absent_components() {
+        let storage = storage();
+        let mut items: Vec<_> = storage.query::<(&u32, Option<&u64>)>().map(|(_, a, b)| (*a, b.copied())).collect();
+        items.sort();
+        assert_eq!(items, [(1, Some(10)), (2, None)]);
+    }
+
+    #[test]
+    fn optional_mutable_elements_write_present_components() {
+        let mut storage = storage();
+        for (_, mut a, b) in storage.query_mut::<(&mut u32, Option<&mut u64>)>() {
+            *a += 1;
+            if let Some(mut b) = b {
+                *b += 1;
+            }
+        }
+
+        let mut items: Vec<_> = storage.query::<(&u32, Option<&u64>)>().map(|(_, a, b)| (*a, b.copied())).collect();
+        items.sort();
+        assert_eq!(items, [(2, Some(11)), (3, None)]);
+    }
+
+    // The entities with both components, whichever of the two sets drives the query.
+    fn pairs(storage: &ECSStorage) -> Vec<(u32, u64)> {
+        let mut pairs: Vec<_> = storage.query::<(&u32, &u64)>().map(|(_, a, b)| (*a, *b)).collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn results_do_not_depend_on_the_driving_set() {
+        let mut storage = ECSStorage::new();
+        let entities: Vec<_> = (0..10u32).map(|i| storage.spawn((i,))).collect();
+        storage.insert_component(entities[3], 30u64);
+        storage.insert_component(entities[7], 70u64);
+
+        // `u64` is the smaller set.
+        assert_eq!(pairs(&storage), [(3, 30), (7, 70)]);
+
+        for i in 10..30u64 {
+            storage.spawn((i,));
+        }
+
+        // Now `u32` is.
+        assert_eq!(pairs(&storage), [(3, 30), (7, 70)]);
+        assert_eq!(storage.query::<(&u64, &u32)>().count(), 2);
+    }
+
+    #[test]
+    fn sixteen_element_queries_iterate() {
+        let mut storage = storage();
+        let entity = storage.spawn((3u32, 30u64));
+        storage.insert_component(entity, 1u8);
+        storage.insert_component(entity, 2u16);
+        storage.insert_component(entity, 4u128);
+        storage.insert_component(entity, 5i8);
+        storage.insert_component(entity, 6i16);
+        storage.insert_component(entity, 7i32);
+        storage.insert_component(entity, 8i64);
+        storage.insert_component(entity, 9i128);
+        storage.insert_component(entity, 10usize);
+        storage.insert_component(entity, 11isize);
+        storage.insert_component(entity, 12f32);
+        storage.insert_component(entity, 13f64);
+        storage.insert_component(entity, true);
+
+        let items: Vec<_> = storage.query::<(
+            Entity, &u8, &u16, &u32, &u64, &u128, &i8, &i16, &i32, &i64, &i128, &usize, &isize, &f32, &f64, &bool,
+        )>().map(|(_, fetched, a, _, c, d, .., last)| (fetched, *a, *c, *d, *last)).collect();
+        assert_eq!(items, [(entity, 1, 3, 30, true)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "mutably more than once")]
+    fn rejects_two_mutable_accesses() {
+        storage().query_mut::<(&mut u32, &mut u32)>().for_each(drop);
+    }
+
+    #[test]
+    #[should_panic(expected = "both mutably and immutably")]
+    fn rejects_mutable_and_shared_access() {
+        storage().query_mut::<(&u32, &mut u32)>().for_each(drop);
+    }
+
+    #[test]
+    #[should_panic(expected = "both mutably and immutably")]
+    fn rejects_optional_aliases() {
+        storage().query_mut::<(&mut u32, Option<&u32>)>().for_each(drop);
+    }
+
+    #[test]
+    #[should_panic(expected = "mutably more than once")]
+    fn rejects_aliases_without_matches() {
+        // The access set is validated even if no entity has the component.
+        ECSStorage::new().query_mut::<(&mut u32, &mut u32)>().for_each(drop);
+    }
+
+    #[test]
+    fn allows_disjoint_and_shared_accesses() {
+        let mut storage = storage();
+        assert_eq!(storage.query::<(&u32, &u32)>().count(), 2);
+
+        for (_, _, mut a, b) in storage.query_mut::<(Entity, &mut u32, &u64)>() {
+            *a += *b as u32;
+        }
+        let mut items: Vec<_> = storage.query::<(&u32,)>().map(|(_, a)| *a).collect();
+        items.sort();
+        assert_eq!(items, [2, 11]);
+    }
+
+    // Every `u32` without a `u64` starts at 0, and `par_for_each` bumps the ones it visits.
+    fn par_for_each_visits_every_entity_once(storage_kind: StorageKind) {
+        let mut storage = ECSStorage::new();
+        storage.set_task_pool(TaskPool::with_threads(4));
+        let id = storage.register_component::<u32>().id();
+        storage.set_storage_kind(id, storage_kind);
+
+        let mut entities: Vec<Entity> = (0..100).map(|_| storage.spawn((0u32,))).collect();
+        storage.spawn_batch((0..20).map(|_| (0u32, 0u64)));
+        // Entities in a second archetype, for table storage.
+        entities.extend((0..50).map(|_| storage.spawn((0u32, 1u8))));
+
+        let visited = Mutex::new(Vec::new());
+        let mut query = storage.query_filtered_mut::<(&mut u32,), (Without<u64>,)>();
+        // Starts in the middle of the first archetype or sparse set.
+        let (first, mut a) = query.next().unwrap();
+        *a += 1;
+        visited.lock().unwrap().push(first);
+
+        query.par_for_each(7, |(entity, mut a)| {
+            *a += 1;
+            visited.lock().unwrap().push(entity);
+        });
+
+        let mut visited = visited.into_inner().unwrap();
+        visited.sort_by_key(|entity| entity.index());
+        entities.sort_by_key(|entity| entity.index());
+        assert_eq!(visited, entities);
+        assert!(storage.query_filtered::<(&u32,), (Without<u64>,)>().all(|(_, a)| *a == 1));
+        assert!(storage.query::<(&u32, &u64)>().all(|(_, a, _)| *a == 0));
+    }
+
+    #[test]
+    fn par_for_each_visits_sparse_set_entities_once() {
+        par_for_each_visits_every_entity_once(StorageKind::SparseSet);
+    }
+
+    #[test]
+    fn par_for_each_visits_table_entities_once() {
+        par_for_each_visits_every_entity_once(StorageKind::Table);
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashSet;
+
+use crate::ecs::{archetype::Archetype, change_detection::{ComponentTicks, Tick}, component::ComponentTypeUUID, entity::Entity, ECSStorage};
+
+use super::{smallest, ComponentFetch, QueryIndices};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynamicAccess
+{
+    Read,
+    Write,
+    OptionalRead,
+    OptionalWrite,
+}
+
+impl DynamicAccess
+{
+    pub fn is_mutable(self) -> bool
+    {
+        matches!(self, DynamicAccess::Write | DynamicAccess::OptionalWrite)
+    }
+
+    pub fn is_optional(self) -> bool
+    {
+        matches!(self, DynamicAccess::OptionalRead | DynamicAccess::OptionalWrite)
+    }
+}
+
+// Describes a query over component ids for callers that do not know the types at compile time.
+// Every `read`/`write`/`optional*` call adds a term, and items expose the terms in that order.
+#[derive(Clone, Default)]
+pub struct QueryBuilder
+{
+    terms: Vec<(ComponentTypeUUID, DynamicAccess)>,
+    with: Vec<ComponentTypeUUID>,
+    without: Vec<ComponentTypeUUID>,
+}
+
+impl QueryBuilder
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn term(mut self, component_type_uuid: ComponentTypeUUID, access: DynamicAccess) -> Self
+    {
+        self.terms.push((component_type_uuid, access));
+        self
+    }
+
+    pub fn read(self, component_type_uuid: ComponentTypeUUID) -> Self
+    {
+        self.term(component_type_uuid, DynamicAccess::Read)
+    }
+
+    pub fn write(self, component_type_uuid: ComponentTypeUUID) -> Self
+    {
+        self.term(component_type_uuid, DynamicAccess::Write)
+    }
+
+    pub fn optional(self, component_type_uuid: ComponentTypeUUID) -> Self
+    {
+        self.term(component_type_uuid, DynamicAccess::OptionalRead)
+    }
+
+    pub fn optional_write(self, component_type_uuid: ComponentTypeUUID) -> Self
+    {
+        self.term(component_type_uuid, DynamicAccess::OptionalWrite)
+    }
+
+    pub fn with(mut self, component_type_uuid: ComponentTypeUUID) -> Self
+    {
+        self.with.push(component_type_uuid);
+        self
+    }
+
+    pub fn without(mut self, component_type_uuid: ComponentTypeUUID) -> Self
+    {
+        self.without.push(component_type_uuid);
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool
+    {
+        !self.terms.iter().any(|(_, access)| access.is_mutable())
+    }
+
+    // Panics if the query writes to a component, use `build_mut` for those.
+    pub fn build<'a>(&self, storage: &'a ECSStorage) -> DynamicQuery<'a>
+    {
+        assert!(self.is_read_only(), "Dynamic query with write access needs a mutable storage, use build_mut");
+        DynamicQuery::new(self, storage)
+    }
+
+    pub fn build_mut<'a>(&self, storage: &'a mut ECSStorage) -> DynamicQuery<'a>
+    {
+        DynamicQuery::new(self, storage)
+    }
+
+    // Same rule as `validate_access` for typed queries.
+    fn validate_access(&self)
+    {
+        for (i, &(component_type_uuid, access)) in self.terms.iter().enumerate()
+        {
+            for &(other_uuid, other_access) in &self.terms[i + 1..]
+            {
+                if component_type_uuid == other_uuid && (access.is_mutable() || other_access.is_mutable())
+                {
+                    panic!("Dynamic query accesses component {} mutably more than once or both mutably and immutably", component_type_uuid);
+                }
+            }
+        }
+    }
+}
+
+struct DynamicTerm<'a>
+{
+    components: Option<ComponentFetch<'a>>,
+    access: DynamicAccess,
+}
+
+pub struct DynamicQuery<'a>
+{
+    storage: &'a ECSStorage,
+    terms: Vec<DynamicTerm<'a>>,
+    required: HashSet<ComponentTypeUUID>,
+    without: Vec<ComponentTypeUUID>,
+    // A required component was never registered, so nothing can match.
+    empty: bool,
+    change_tick: Tick,
+}
+
+impl<'a> DynamicQuery<'a>
+{
+    fn new(builder: &QueryBuilder, storage: &'a ECSStorage) -> Self
+    {
+        builder.validate_access();
+
+        let mut empty = false;
+        let mut required = HashSet::new();
+        let mut terms = Vec::with_capacity(builder.terms.len());
+
+        for &(component_type_uuid, access) in &builder.terms
+        {
+            let components = ComponentFetch::new(storage, component_type_uuid);
+
+            if !access.is_optional()
+            {
+                empty |= components.is_none();
+                required.insert(component_type_uuid);
+            }
+
+            terms.push(DynamicTerm { components, access });
+        }
+
+        for &component_type_uuid in &builder.with
+        {
+            empty |= storage.component_registry().info(component_type_uuid).is_none();
+            required.insert(component_type_uuid);
+        }
+
+        Self
+        {
+            storage,
+            terms,
+            required,
+            without: builder.without.clone(),
+            empty,
+            change_tick: storage.change_tick(),
+        }
+    }
+
+    pub fn iter(&self) -> DynamicQueryIter<'_, 'a>
+    {
+        let driver = (!self.empty).then(|| self.terms.iter()
+            .filter(|term| !term.access.is_optional())
+            .fold(None, |driver, term| smallest(driver, term.components.and_then(|components| components.driver()))));
+
+        DynamicQueryIter
+        {
+            query: self,
+            indices: QueryIndices::new(self.storage, driver),
+        }
+    }
+}
+
+pub struct DynamicQueryIter<'q, 'a>
+{
+    query: &'q DynamicQuery<'a>,
+    indices: QueryIndices<'a>,
+}
+
+impl<'q, 'a> Iterator for DynamicQueryIter<'q, 'a>
+{
+    type Item = DynamicQueryItem<'q, 'a>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let query = self.query;
+
+        let matches = |archetype: &Archetype| query.terms.iter()
+            .filter(|term| !term.access.is_optional())
+            .all(|term| term.components.is_some_and(|components| components.matches_archetype(archetype)));
+
+        while let Some((index, archetype, row)) = self.indices.next(query.storage, matches)
+        {
+            let bit_set = &query.storage.entity_components_bitset[&index];
+
+            if query.required.iter().all(|&component_type_uuid| bit_set.get(component_type_uuid))
+                && !query.without.iter().any(|&component_type_uuid| bit_set.get(component_type_uuid))
+            {
+                return Some(DynamicQueryItem
+                {
+                    query,
+                    entity: query.storage.entity_at_index(index),
+                    archetype,
+                    row,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+// Pointers handed out here are valid until the query is dropped. Writing is only allowed through
+// `get_mut`, which is what stamps the change tick.
+pub struct DynamicQueryItem<'q, 'a>
+{
+    query: &'q DynamicQuery<'a>,
+    entity: Entity,
+    archetype: &'a Archetype,
+    row: usize,
+}
+
+impl DynamicQueryItem<'_, '_>
+{
+    pub fn entity(&self) -> Entity
+    {
+        self.entity
+    }
+
+    fn component(&self, term: usize) -> Option<(*mut u8, *mut ComponentTicks)>
+    {
+        let mut components = self.query.terms[term].components?;
+        components.set_archetype(self.archetype);
+        components.get(self.entity, self.row)
+    }
+
+    // `None` for an optional term the entity does not have.
+    pub fn get(&self, term: usize) -> Option<*const u8>
+    {
+        self.component(term).map(|(component, _)| component as *const u8)
+    }
+
+    // Panics if the term was not added with write access.
+    pub fn get_mut(&self, term: usize) -> Option<*mut u8>
+    {
+        assert!(self.query.terms[term].access.is_mutable(), "Dynamic query term is read-only");
+
+        let (ptr, ticks) = self.component(term)?;
+
+        // Safety: write terms are only built from a mutably borrowed storage, and `validate_access`
+        // made sure no other term of this query touches the same component.
+        unsafe { (*ticks).changed = self.query.change_tick };
+
+        Some(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::QueryBuilder;
+    use crate::ecs::{entity::Entity, query::filter::Changed, ECSStorage};
+
+    // Entities with `u32` only, `u32` and `u64`, and `u32` and `u8`.
+    fn storage() -> (ECSStorage, [Entity; 3])
+    {
+        let mut storage = ECSStorage::new();
+        let entities = [storage.spawn((1u32,)), storage.spawn((2u32, 20u64)), storage.spawn((3u32, 30u8))];
+        (storage, entities)
+    }
+
+    fn sorted(mut entities: Vec<Entity>) -> Vec<Entity>
+    {
+        entities.sort_by_key(|entity| entity.index());
+        entities
+    }
+
+    #[test]
+    fn queries_match_the_required_components()
+    {
+        let (mut storage, [first, second, third]) = storage();
+        let (a, b) = (storage.register_component::<u32>().id(), storage.register_component::<u64>().id());
+
+        let query = QueryBuilder::new().read(a).build(&storage);
+        assert_eq!(sorted(query.iter().map(|item| item.entity()).collect()), [first, second, third]);
+
+        let query = QueryBuilder::new().read(a).read(b).build(&storage);
+        let items: Vec<_> = query.iter().map(|item| unsafe { (*(item.get(0).unwrap() as *const u32), *(item.get(1).unwrap() as *const u64)) }).collect();
+        assert_eq!(items, [(2, 20)]);
+
+        // A component nobody registered matches nothing.
+        assert_eq!(QueryBuilder::new().read(a).read(1000).build(&storage).iter().count(), 0);
+    }
+
+    #[test]
+    fn optional_terms_are_none_for_missing_components()
+    {
+        let (mut storage, [first, second, third]) = storage();
+        let (a, b) = (storage.register_component::<u32>().id(), storage.register_component::<u64>().id());
+
+        let query = QueryBuilder::new().read(a).optional(b).build(&storage);
+        let mut items: Vec<_> = query.iter().map(|item| (item.entity(), item.get(1).map(|ptr| unsafe { *(ptr as *const u64) }))).collect();
+        items.sort_by_key(|(entity, _)| entity.index());
+        assert_eq!(items, [(first, None), (second, Some(20)), (third, None)]);
+
+        // Optional terms of unregistered components are `None` for every entity.
+        let query = QueryBuilder::new().read(a).optional(1000).build(&storage);
+        assert!(query.iter().all(|item| item.get(1).is_none()));
+    }
+
+    #[test]
+    fn with_and_without_filter_entities()
+    {
+        let (mut storage, [first, second, third]) = storage();
+        let a = storage.register_component::<u32>().id();
+        let b = storage.register_component::<u64>().id();
+        let c = storage.register_component::<u8>().id();
+
+        let entities = |builder: QueryBuilder| sorted(builder.build(&storage).iter().map(|item| item.entity()).collect());
+        assert_eq!(entities(QueryBuilder::new().read(a).with(b)), [second]);
+        assert_eq!(entities(QueryBuilder::new().read(a).without(b)), [first, third]);
+        assert_eq!(entities(QueryBuilder::new().read(a).without(b).without(c)), [first]);
+        assert!(entities(QueryBuilder::new().read(a).with(1000)).is_empty());
+    }
+
+    #[test]
+    fn writes_are_visible_to_changed()
+    {
+        let (mut storage, [first, ..]) = storage();
+        let a = storage.register_component::<u32>().id();
+        let last_change_tick = storage.increment_change_tick();
+        storage.set_last_change_tick(last_change_tick);
+        assert_eq!(storage.query_filtered::<(&u32,), (Changed<u32>,)>().count(), 0);
+
+        let query = QueryBuilder::new().write(a).build_mut(&mut storage);
+        for item in query.iter().filter(|item| item.entity() == first)
+        {
+            unsafe { *(item.get_mut(0).unwrap() as *mut u32) += 10 };
+        }
+
+        let changed: Vec<_> = storage.query_filtered::<(&u32,), (Changed<u32>,)>().map(|(entity, value)| (entity, *value)).collect();
+        assert_eq!(changed, [(first, 11)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Dynamic query term is read-only")]
+    fn reads_cannot_be_written()
+    {
+        let (mut storage, _) = storage();
+        let a = storage.register_component::<u32>().id();
+        let query = QueryBuilder::new().read(a).build_mut(&mut storage);
+        query.iter().for_each(|item| { item.get_mut(0); });
+    }
+
+    #[test]
+    #[should_panic(expected = "mutably more than once")]
+    fn rejects_two_writes()
+    {
+        let (mut storage, _) = storage();
+        let a = storage.register_component::<u32>().id();
+        QueryBuilder::new().write(a).optional_write(a).build_mut(&mut storage);
+    }
+
+    #[test]
+    #[should_panic(expected = "both mutably and immutably")]
+    fn rejects_write_and_read()
+    {
+        let (mut storage, _) = storage();
+        let a = storage.register_component::<u32>().id();
+        QueryBuilder::new().read(a).write(a).build_mut(&mut storage);
+    }
+
+    #[test]
+    fn allows_repeated_reads()
+    {
+        let (mut storage, _) = storage();
+        let a = storage.register_component::<u32>().id();
+        assert_eq!(QueryBuilder::new().read(a).optional(a).build(&storage).iter().count(), 3);
+    }
+}
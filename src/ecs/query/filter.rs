@@ -0,0 +1,236 @@
+use std::marker::PhantomData;
+
+use crate::data_structures::bit_set::BitSet;
+use crate::ecs::{archetype::Archetype, change_detection::{SystemTicks, Tick}, component::ComponentTypeUUID, entity::Entity, ECSStorage};
+
+use super::{ComponentAccess, ComponentFetch};
+
+// Filters only look at the component bitset or the change ticks of an entity, never at the component data.
+pub trait QueryFilter<'a>
+{
+    type State: Copy;
+
+    // Lets queries skip the bitset lookup entirely when nothing is filtered.
+    const UNFILTERED: bool = false;
+
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Self::State;
+
+    // Called before matching entities of another archetype than the previous one.
+    fn set_archetype(_state: &mut Self::State, _archetype: &'a Archetype) {}
+
+    // `row` is the entity's row in the archetype last passed to `set_archetype`.
+    fn matches(state: &Self::State, entity: Entity, bit_set: &BitSet, row: usize) -> bool;
+
+    // The components whose change ticks the filter reads; bitset lookups do not count.
+    fn access() -> Vec<ComponentAccess>
+    {
+        Vec::new()
+    }
+}
+
+pub struct With<T>(PhantomData<T>);
+pub struct Without<T>(PhantomData<T>);
+pub struct Or<T>(PhantomData<T>);
+pub struct Added<T>(PhantomData<T>);
+pub struct Changed<T>(PhantomData<T>);
+
+impl<'a, T: 'static> QueryFilter<'a> for With<T>
+{
+    type State = Option<ComponentTypeUUID>;
+
+    fn init(storage: &'a ECSStorage, _: SystemTicks) -> Self::State
+    {
+        storage.component_type_uuid::<T>()
+    }
+
+    fn matches(state: &Self::State, _: Entity, bit_set: &BitSet, _: usize) -> bool
+    {
+        state.is_some_and(|component_type_uuid| bit_set.get(component_type_uuid))
+    }
+}
+
+impl<'a, T: 'static> QueryFilter<'a> for Without<T>
+{
+    type State = Option<ComponentTypeUUID>;
+
+    fn init(storage: &'a ECSStorage, _: SystemTicks) -> Self::State
+    {
+        storage.component_type_uuid::<T>()
+    }
+
+    fn matches(state: &Self::State, _: Entity, bit_set: &BitSet, _: usize) -> bool
+    {
+        !state.is_some_and(|component_type_uuid| bit_set.get(component_type_uuid))
+    }
+}
+
+// Both change filters compare against the last run of the current system, see `SystemTicks`.
+impl<'a, T: 'static> QueryFilter<'a> for Added<T>
+{
+    type State = (Option<ComponentFetch<'a>>, Tick);
+
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Self::State
+    {
+        (ComponentFetch::of::<T>(storage), ticks.last_change_tick)
+    }
+
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype)
+    {
+        if let Some(components) = &mut state.0
+        {
+            components.set_archetype(archetype);
+        }
+    }
+
+    fn matches(state: &Self::State, entity: Entity, _: &BitSet, row: usize) -> bool
+    {
+        let (components, last_change_tick) = *state;
+        // Safety: no live item of the query can refer to this entity yet, since each entity is visited once.
+        components.and_then(|components| components.get(entity, row)).is_some_and(|(_, ticks)| unsafe { (*ticks).is_added(last_change_tick) })
+    }
+
+    fn access() -> Vec<ComponentAccess>
+    {
+        vec![ComponentAccess::of::<T>(false)]
+    }
+}
+
+impl<'a, T: 'static> QueryFilter<'a> for Changed<T>
+{
+    type State = (Option<ComponentFetch<'a>>, Tick);
+
+    fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Self::State
+    {
+        (ComponentFetch::of::<T>(storage), ticks.last_change_tick)
+    }
+
+    fn set_archetype(state: &mut Self::State, archetype: &'a Archetype)
+    {
+        if let Some(components) = &mut state.0
+        {
+            components.set_archetype(archetype);
+        }
+    }
+
+    fn matches(state: &Self::State, entity: Entity, _: &BitSet, row: usize) -> bool
+    {
+        let (components, last_change_tick) = *state;
+        // Safety: no live item of the query can refer to this entity yet, since each entity is visited once.
+        components.and_then(|components| components.get(entity, row)).is_some_and(|(_, ticks)| unsafe { (*ticks).is_changed(last_change_tick) })
+    }
+
+    fn access() -> Vec<ComponentAccess>
+    {
+        vec![ComponentAccess::of::<T>(false)]
+    }
+}
+
+macro_rules! impl_query_filter
+{
+    ($($name:ident),*) =>
+    {
+        #[allow(non_snake_case, unused_variables, unused_mut, clippy::unused_unit)]
+        impl<'a, $($name: QueryFilter<'a>),*> QueryFilter<'a> for ($($name,)*)
+        {
+            type State = ($($name::State,)*);
+
+            const UNFILTERED: bool = true $(&& $name::UNFILTERED)*;
+
+            fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Self::State
+            {
+                ($($name::init(storage, ticks),)*)
+            }
+
+            fn set_archetype(state: &mut Self::State, archetype: &'a Archetype)
+            {
+                let ($($name,)*) = state;
+                $( $name::set_archetype($name, archetype); )*
+            }
+
+            fn matches(state: &Self::State, entity: Entity, bit_set: &BitSet, row: usize) -> bool
+            {
+                let ($($name,)*) = state;
+                true $(&& $name::matches($name, entity, bit_set, row))*
+            }
+
+            fn access() -> Vec<ComponentAccess>
+            {
+                let mut accesses = Vec::new();
+                $( accesses.extend($name::access()); )*
+                accesses
+            }
+        }
+
+        #[allow(non_snake_case, unused_variables, unused_mut, clippy::unused_unit)]
+        impl<'a, $($name: QueryFilter<'a>),*> QueryFilter<'a> for Or<($($name,)*)>
+        {
+            type State = ($($name::State,)*);
+
+            fn init(storage: &'a ECSStorage, ticks: SystemTicks) -> Self::State
+            {
+                ($($name::init(storage, ticks),)*)
+            }
+
+            fn set_archetype(state: &mut Self::State, archetype: &'a Archetype)
+            {
+                let ($($name,)*) = state;
+                $( $name::set_archetype($name, archetype); )*
+            }
+
+            fn matches(state: &Self::State, entity: Entity, bit_set: &BitSet, row: usize) -> bool
+            {
+                let ($($name,)*) = state;
+                false $(|| $name::matches($name, entity, bit_set, row))*
+            }
+
+            fn access() -> Vec<ComponentAccess>
+            {
+                let mut accesses = Vec::new();
+                $( accesses.extend($name::access()); )*
+                accesses
+            }
+        }
+    };
+}
+
+all_tuples!(@with_unit impl_query_filter);
+
+#[cfg(test)]
+mod tests
+{
+    use crate::ecs::{entity::Entity, ECSStorage};
+
+    use super::{Or, With, Without};
+
+    fn values<'a>(items: impl Iterator<Item = (Entity, &'a u32)>) -> Vec<u32>
+    {
+        let mut values: Vec<u32> = items.map(|(_, &value)| value).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn filters_match_on_the_components_an_entity_has()
+    {
+        let mut storage = ECSStorage::new();
+        storage.spawn((1u32,));
+        storage.spawn((2u32, 0u64));
+        storage.spawn((3u32, 0u8));
+        storage.spawn((4u32, 0u64, 0u8));
+
+        assert_eq!(values(storage.query_filtered::<(&u32,), ()>()), [1, 2, 3, 4]);
+        assert_eq!(values(storage.query_filtered::<(&u32,), (With<u64>,)>()), [2, 4]);
+        assert_eq!(values(storage.query_filtered::<(&u32,), (Without<u64>,)>()), [1, 3]);
+        assert_eq!(values(storage.query_filtered::<(&u32,), (With<u64>, Without<u8>)>()), [2]);
+        assert_eq!(values(storage.query_filtered::<(&u32,), (Or<(With<u64>, With<u8>)>,)>()), [2, 3, 4]);
+        assert_eq!(values(storage.query_filtered_mut::<(&mut u32,), (Without<u64>, Without<u8>)>().map(|(entity, value)| (entity, &*value.into_inner()))), [1]);
+
+        // Types that were never registered match nothing, and their absence matches everything.
+        assert!(values(storage.query_filtered::<(&u32,), (With<i8>,)>()).is_empty());
+        assert_eq!(values(storage.query_filtered::<(&u32,), (Without<i8>,)>()), [1, 2, 3, 4]);
+
+        type Sixteen = (With<u64>, With<u64>, With<u64>, With<u64>, With<u64>, With<u64>, With<u64>, With<u64>,
+            With<u64>, With<u64>, With<u64>, With<u64>, With<u64>, With<u64>, With<u64>, With<u8>);
+        assert_eq!(values(storage.query_filtered::<(&u32,), Sixteen>()), [4]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{marker::PhantomData, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, Weak}};
+
+use super::{entity::Entity, ECSStorage};
+
+// Append-only log of entities that every reader reads at its own pace. Nothing is dropped by
+// updates: `trim` only drops the entries every live reader has already read, so a reader that runs
+// rarely still sees every entry logged since its last read.
+pub struct EntityLog
+{
+    entities: Vec<Entity>,
+    // Number of entries dropped from the front; cursors count from the first entry ever logged.
+    trimmed: usize,
+    cursors: Mutex<Vec<Weak<AtomicUsize>>>,
+}
+
+impl EntityLog
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            entities: Vec::new(),
+            trimmed: 0,
+            cursors: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn send(&mut self, entity: Entity)
+    {
+        self.entities.push(entity);
+    }
+
+    // The number of entries still kept, read or not.
+    pub fn len(&self) -> usize
+    {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entities.is_empty()
+    }
+
+    // Drops the entries read by every live reader. Without readers, everything is dropped.
+    pub fn trim(&mut self)
+    {
+        let end = self.trimmed + self.entities.len();
+
+        let cursors = self.cursors.get_mut().unwrap();
+        cursors.retain(|cursor| cursor.strong_count() > 0);
+
+        let slowest = cursors.iter()
+            .filter_map(Weak::upgrade)
+            .map(|cursor| cursor.load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(end);
+
+        self.entities.drain(..slowest - self.trimmed);
+        self.trimmed = slowest;
+    }
+
+    fn register(&self) -> Arc<AtomicUsize>
+    {
+        let cursor = Arc::new(AtomicUsize::new(self.trimmed));
+        self.cursors.lock().unwrap().push(Arc::downgrade(&cursor));
+        cursor
+    }
+}
+
+impl Default for EntityLog
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+// Reads one `EntityLog`, seeing every entry at most once. The first read registers the reader with
+// the log and returns everything the log still keeps; dropping the reader stops it holding entries back.
+pub struct EntityLogReader
+{
+    cursor: Option<Arc<AtomicUsize>>,
+}
+
+impl EntityLogReader
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            cursor: None,
+        }
+    }
+
+    pub fn read<'a>(&mut self, log: &'a EntityLog) -> impl Iterator<Item = Entity> + 'a
+    {
+        let cursor = self.cursor.get_or_insert_with(|| log.register());
+
+        let start = cursor.load(Ordering::Relaxed).max(log.trimmed) - log.trimmed;
+        cursor.store(log.trimmed + log.entities.len(), Ordering::Relaxed);
+
+        log.entities[start..].iter().copied()
+    }
+}
+
+impl Default for EntityLogReader
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+// Lists the entities that lost a `T`, through `remove_component` or `remove_entity`,
+// since this reader last read.
+pub struct RemovedComponents<T>
+{
+    reader: EntityLogReader,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> RemovedComponents<T>
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            reader: EntityLogReader::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn read<'a>(&mut self, storage: &'a ECSStorage) -> impl Iterator<Item = Entity> + 'a
+    {
+        storage.removed_component_log::<T>()
+            .map(|log| self.reader.read(log))
+            .into_iter()
+            .flatten()
+    }
+}
+
+impl<T: 'static> Default for RemovedComponents<T>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::RemovedComponents;
+    use crate::ecs::{removal_detection::EntityLogReader, ECS};
+
+    #[test]
+    fn readers_see_removals_across_any_number_of_updates()
+    {
+        let mut ecs = ECS::new();
+        let kept = ecs.spawn((0u32, 0u64));
+        let despawned = ecs.spawn((1u32,));
+
+        let mut every_update = RemovedComponents::<u32>::new();
+        let mut rarely = RemovedComponents::<u32>::new();
+        let mut despawns = EntityLogReader::new();
+        assert_eq!(every_update.read(ecs.storage()).count(), 0);
+        assert_eq!(rarely.read(ecs.storage()).count(), 0);
+        assert_eq!(despawns.read(ecs.storage().despawned_entities()).count(), 0);
+
+        ecs.storage_mut().remove_component::<u32>(kept);
+        ecs.storage_mut().remove_entity(despawned);
+
+        for _ in 0..5
+        {
+            ecs.update();
+            every_update.read(ecs.storage()).for_each(drop);
+        }
+
+        assert_eq!(rarely.read(ecs.storage()).collect::<Vec<_>>(), [kept, despawned]);
+        assert_eq!(rarely.read(ecs.storage()).count(), 0);
+        assert_eq!(despawns.read(ecs.storage().despawned_entities()).collect::<Vec<_>>(), [despawned]);
+    }
+
+    #[test]
+    fn trimming_keeps_entries_until_the_slowest_reader_read_them()
+    {
+        let mut ecs = ECS::new();
+        let entity = ecs.spawn((0u32,));
+
+        let mut fast = RemovedComponents::<u32>::new();
+        let mut slow = RemovedComponents::<u32>::new();
+        fast.read(ecs.storage()).for_each(drop);
+        slow.read(ecs.storage()).for_each(drop);
+
+        ecs.storage_mut().remove_component::<u32>(entity);
+        assert_eq!(fast.read(ecs.storage()).count(), 1);
+
+        ecs.update();
+        assert_eq!(ecs.storage().removed_component_log::<u32>().map(|log| log.len()), Some(1));
+
+        assert_eq!(slow.read(ecs.storage()).count(), 1);
+        ecs.update();
+        assert_eq!(ecs.storage().removed_component_log::<u32>().map(|log| log.len()), Some(0));
+
+        // A dropped reader no longer holds entries back.
+        ecs.storage_mut().insert_component(entity, 1u32);
+        ecs.storage_mut().remove_component::<u32>(entity);
+        drop(slow);
+        fast.read(ecs.storage()).for_each(drop);
+        ecs.update();
+        assert_eq!(ecs.storage().removed_component_log::<u32>().map(|log| log.len()), Some(0));
+    }
+}
@@ -0,0 +1,734 @@
+use std::{any::TypeId, collections::{BTreeSet, HashMap}, error::Error, fmt, ops::Range};
+
+use super::{change_detection::Tick, system::{function::IntoSystem, PhaseSystem, RunSystem}, ECSStorage};
+
+// Names a schedule. Labels are types, usually unit structs:
+// `struct PreUpdate; impl ScheduleLabel for PreUpdate {}`.
+pub trait ScheduleLabel: 'static {}
+
+// The built-in schedules, run by `ECS::start`, `update`, `fixed_update` and `render`. `System` impls
+// run in all four, each with the matching method.
+pub struct Start;
+pub struct Update;
+pub struct FixedUpdate;
+pub struct Render;
+
+impl ScheduleLabel for Start {}
+impl ScheduleLabel for Update {}
+impl ScheduleLabel for FixedUpdate {}
+impl ScheduleLabel for Render {}
+
+pub enum SystemKind
+{
+    // Always runs alone, since it gets the whole storage.
+    Phase(PhaseSystem),
+    Function(Box<dyn RunSystem>),
+}
+
+struct SystemNode
+{
+    id: TypeId,
+    name: &'static str,
+    kind: SystemKind,
+    before: Vec<TypeId>,
+    after: Vec<TypeId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError
+{
+    // The systems along the cycle, the first one repeated at the end.
+    Cycle(Vec<&'static str>),
+}
+
+impl fmt::Display for ScheduleError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ScheduleError::Cycle(systems) => write!(f, "System order contains a cycle: {}", systems.join(" -> ")),
+        }
+    }
+}
+
+impl Error for ScheduleError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutorKind
+{
+    #[default]
+    SingleThreaded,
+    // Runs function systems with compatible accesses on scoped threads; `System` impls still run alone.
+    MultiThreaded,
+}
+
+struct ResolvedOrder
+{
+    // Indices into `Schedule::systems`.
+    systems: Vec<usize>,
+    // Consecutive ranges of `systems` that may run at the same time.
+    stages: Vec<Range<usize>>,
+}
+
+// Lets scoped threads share the storage. Only systems of one stage get to see it, and their
+// accesses are compatible, so they never touch the same component or resource mutably.
+#[derive(Clone, Copy)]
+struct SharedStorage<'a>(&'a ECSStorage);
+
+unsafe impl Send for SharedStorage<'_> {}
+unsafe impl Sync for SharedStorage<'_> {}
+
+impl<'a> SharedStorage<'a>
+{
+    // Closures capturing the field directly would capture a plain `&ECSStorage`, which is not `Send`.
+    fn get(self) -> &'a ECSStorage
+    {
+        self.0
+    }
+}
+
+// Systems run in registration order, unless `before`/`after` constraints say otherwise.
+// Constraints naming systems that are not registered are ignored.
+pub struct Schedule
+{
+    systems: Vec<SystemNode>,
+    // Resolved lazily and dropped whenever systems or constraints change.
+    order: Option<ResolvedOrder>,
+    executor: ExecutorKind,
+}
+
+impl Schedule
+{
+    pub fn new() -> Self
+    {
+        Self::with_executor(ExecutorKind::default())
+    }
+
+    pub fn with_executor(executor: ExecutorKind) -> Self
+    {
+        Self
+        {
+            systems: Vec::new(),
+            order: None,
+            executor,
+        }
+    }
+
+    // Returns the index to constrain the system with, see `SystemConfig`.
+    // A system of the same type as an earlier one replaces it in place, constraints included.
+    pub(super) fn insert(&mut self, id: TypeId, name: &'static str, kind: SystemKind) -> usize
+    {
+        let node = SystemNode
+        {
+            id,
+            name,
+            kind,
+            before: Vec::new(),
+            after: Vec::new(),
+        };
+
+        let index = match self.systems.iter().position(|system| system.id == id)
+        {
+            Some(index) =>
+            {
+                self.systems[index] = node;
+                index
+            }
+            None =>
+            {
+                self.systems.push(node);
+                self.systems.len() - 1
+            }
+        };
+
+        self.order = None;
+
+        index
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.systems.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.systems.is_empty()
+    }
+
+    pub fn executor(&self) -> ExecutorKind
+    {
+        self.executor
+    }
+
+    pub fn set_executor(&mut self, executor: ExecutorKind)
+    {
+        self.executor = executor;
+    }
+
+    // The system names in the order they run.
+    pub fn order(&mut self) -> Result<Vec<&'static str>, ScheduleError>
+    {
+        self.resolve()?;
+        Ok(self.order.as_ref().unwrap().systems.iter().map(|&index| self.systems[index].name).collect())
+    }
+
+    // Panics if the constraints contain a cycle.
+    pub fn run(&mut self, storage: &mut ECSStorage)
+    {
+        self.resolve_or_panic();
+
+        let Self { systems, order, executor } = self;
+        let order = order.as_ref().unwrap();
+
+        for stage in &order.stages
+        {
+            let stage = &order.systems[stage.clone()];
+            if *executor == ExecutorKind::SingleThreaded || stage.len() == 1
+            {
+                for &index in stage
+                {
+                    match &mut systems[index].kind
+                    {
+                        SystemKind::Phase(system) => system.run(storage),
+                        SystemKind::Function(system) => system.run(storage),
+                    }
+                }
+            }
+            else
+            {
+                Self::run_parallel(systems, stage, storage);
+            }
+        }
+    }
+
+    // `stage` only holds function systems with compatible accesses, see `stages`.
+    fn run_parallel(systems: &mut [SystemNode], stage: &[usize], storage: &mut ECSStorage)
+    {
+        let mut slots: Vec<_> = systems.iter_mut().map(Some).collect();
+        let mut stage_systems: Vec<&mut Box<dyn RunSystem>> = stage.iter()
+            .map(|&index| match &mut slots[index].take().unwrap().kind
+            {
+                SystemKind::Function(system) => system,
+                SystemKind::Phase(_) => unreachable!("`System` impls always run alone"),
+            })
+            .collect();
+
+        // Every system gets its own tick, as if they had run one after another.
+        let change_ticks: Vec<Tick> = (0..stage.len() as Tick).map(|offset| storage.change_tick() + offset).collect();
+
+        let shared = SharedStorage(storage);
+        std::thread::scope(|scope|
+        {
+            let mut runs = stage_systems.iter_mut().zip(change_ticks);
+            let (first, first_change_tick) = runs.next().unwrap();
+
+            for (system, change_tick) in runs
+            {
+                // Safety: see `SharedStorage`.
+                scope.spawn(move || unsafe { system.run_unsafe(shared.get(), change_tick) });
+            }
+
+            // Safety: see `SharedStorage`.
+            unsafe { first.run_unsafe(shared.get(), first_change_tick) };
+        });
+
+        // Only the last system of a stage defers work, which is then applied at its own tick like in a
+        // single-threaded run.
+        for system in stage_systems
+        {
+            system.apply_deferred(storage);
+            storage.increment_change_tick();
+        }
+    }
+
+    fn resolve_or_panic(&mut self)
+    {
+        if let Err(error) = self.resolve()
+        {
+            panic!("{}", error);
+        }
+    }
+
+    // Leaves the order in `self.order` on success.
+    fn resolve(&mut self) -> Result<(), ScheduleError>
+    {
+        if self.order.is_none()
+        {
+            self.order = Some(self.sort()?);
+        }
+
+        Ok(())
+    }
+
+    // Kahn's algorithm, always taking the earliest registered system that is ready, so
+    // unconstrained systems keep their registration order.
+    fn sort(&self) -> Result<ResolvedOrder, ScheduleError>
+    {
+        let indices: HashMap<TypeId, usize> = self.systems.iter().enumerate().map(|(index, system)| (system.id, index)).collect();
+
+        let mut successors = vec![Vec::new(); self.systems.len()];
+        let mut predecessors = vec![Vec::new(); self.systems.len()];
+        for (index, system) in self.systems.iter().enumerate()
+        {
+            let edges = system.before.iter().filter_map(|id| indices.get(id)).map(|&other| (index, other))
+                .chain(system.after.iter().filter_map(|id| indices.get(id)).map(|&other| (other, index)));
+
+            for (from, to) in edges
+            {
+                successors[from].push(to);
+                predecessors[to].push(from);
+            }
+        }
+
+        let mut in_degrees: Vec<usize> = predecessors.iter().map(Vec::len).collect();
+        let mut ready: BTreeSet<usize> = (0..self.systems.len()).filter(|&index| in_degrees[index] == 0).collect();
+        let mut order = Vec::with_capacity(self.systems.len());
+
+        while let Some(index) = ready.pop_first()
+        {
+            order.push(index);
+            for &successor in &successors[index]
+            {
+                in_degrees[successor] -= 1;
+                if in_degrees[successor] == 0
+                {
+                    ready.insert(successor);
+                }
+            }
+        }
+
+        if order.len() < self.systems.len()
+        {
+            return Err(ScheduleError::Cycle(self.find_cycle(&predecessors, &in_degrees)));
+        }
+
+        Ok(ResolvedOrder
+        {
+            stages: self.stages(&order, &predecessors),
+            systems: order,
+        })
+    }
+
+    // Splits the order into runs of function systems with compatible accesses and no constraint
+    // between them. A system that defers work, e.g. through `Commands`, ends its stage, since later
+    // systems would have seen that work applied. No system of a stage depends on another one of it,
+    // so running the stages one after another gives the same result as running the systems one by one.
+    fn stages(&self, order: &[usize], predecessors: &[Vec<usize>]) -> Vec<Range<usize>>
+    {
+        let mut stages = Vec::new();
+        let mut start = 0;
+
+        for (position, &index) in order.iter().enumerate()
+        {
+            let stage = &order[start..position];
+            let joins = match &self.systems[index].kind
+            {
+                SystemKind::Function(system) => stage.iter().all(|&other| match &self.systems[other].kind
+                {
+                    SystemKind::Function(other_system) => !other_system.access().has_deferred()
+                        && !predecessors[index].contains(&other)
+                        && system.access().is_compatible(other_system.access()),
+                    SystemKind::Phase(_) => false,
+                }),
+                SystemKind::Phase(_) => stage.is_empty(),
+            };
+
+            if !joins
+            {
+                stages.push(start..position);
+                start = position;
+            }
+        }
+
+        if start < order.len()
+        {
+            stages.push(start..order.len());
+        }
+
+        stages
+    }
+
+    // Every system left over by `sort` still waits on another left over system, so walking
+    // backwards along those edges has to come back to a system it already passed.
+    fn find_cycle(&self, predecessors: &[Vec<usize>], in_degrees: &[usize]) -> Vec<&'static str>
+    {
+        let mut path = vec![in_degrees.iter().position(|&in_degree| in_degree > 0).unwrap()];
+
+        loop
+        {
+            let current = *path.last().unwrap();
+            let previous = predecessors[current].iter().copied().find(|&index| in_degrees[index] > 0).unwrap();
+
+            if let Some(start) = path.iter().position(|&index| index == previous)
+            {
+                let mut cycle: Vec<_> = path[start..].iter().rev().map(|&index| self.systems[index].name).collect();
+                cycle.push(cycle[0]);
+                return cycle;
+            }
+
+            path.push(previous);
+        }
+    }
+}
+
+impl Default for Schedule
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+// Returned when adding a system, to constrain where it runs relative to others. `System` impls
+// are in every built-in schedule, so their constraints apply to each of them.
+pub struct SystemConfig<'a>
+{
+    systems: Vec<(&'a mut Schedule, usize)>,
+}
+
+impl<'a> SystemConfig<'a>
+{
+    // Pairs of a schedule and the index `Schedule::insert` returned.
+    pub(super) fn new(systems: Vec<(&'a mut Schedule, usize)>) -> Self
+    {
+        Self
+        {
+            systems,
+        }
+    }
+
+    // `S` is the type of a `System` impl or a function system.
+    pub fn before<S>(self) -> Self where S: 'static
+    {
+        self.constrain(|system| system.before.push(TypeId::of::<S>()))
+    }
+
+    pub fn after<S>(self) -> Self where S: 'static
+    {
+        self.constrain(|system| system.after.push(TypeId::of::<S>()))
+    }
+
+    // Function types cannot be named, so function systems are passed by value instead.
+    pub fn before_system<Marker, S>(self, _system: S) -> Self where S: IntoSystem<Marker> + 'static
+    {
+        self.before::<S>()
+    }
+
+    pub fn after_system<Marker, S>(self, _system: S) -> Self where S: IntoSystem<Marker> + 'static
+    {
+        self.after::<S>()
+    }
+
+    fn constrain(mut self, constrain: impl Fn(&mut SystemNode)) -> Self
+    {
+        for (schedule, index) in &mut self.systems
+        {
+            constrain(&mut schedule.systems[*index]);
+            schedule.order = None;
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::ecs::{command::Commands, system::{param::{Query, Res, ResMut}, System}, ECSStorage, ECS};
+
+    use super::{ExecutorKind, FixedUpdate, Render, ScheduleError, ScheduleLabel, Start, SystemKind, Update};
+
+    struct Physics;
+
+    impl System for Physics
+    {
+        fn new() -> Self
+        {
+            Self
+        }
+    }
+
+    fn input() {}
+    fn movement() {}
+    fn render() {}
+
+    struct A(u32);
+    struct B;
+    struct Time(u32);
+    struct SeenA(usize);
+
+    fn write_a(mut query: Query<(&mut A,)>)
+    {
+        for (_, mut a) in &mut query
+        {
+            a.0 += 1;
+        }
+    }
+
+    fn read_a(_: Query<(&A,)>) {}
+    fn write_b(_: Query<(&mut B,)>) {}
+    fn read_a_and_b(_: Query<(&A, &B)>) {}
+    fn read_time(_: Res<Time>) {}
+    fn write_time(mut time: ResMut<Time>)
+    {
+        time.0 += 1;
+    }
+
+    fn spawn_a(mut commands: Commands)
+    {
+        commands.spawn((A(0),));
+    }
+
+    fn count_a(query: Query<(&A,)>, mut seen: ResMut<SeenA>)
+    {
+        seen.0 = query.iter().count();
+    }
+
+    // The order without module paths, e.g. `movement` or `Physics`.
+    fn order<L>(ecs: &mut ECS, label: L) -> Result<Vec<&'static str>, ScheduleError> where L: ScheduleLabel
+    {
+        ecs.system_order(label).map(|order| order.into_iter().map(|name| name.rsplit("::").next().unwrap()).collect())
+    }
+
+    #[test]
+    fn systems_run_in_registration_order_by_default()
+    {
+        let mut ecs = ECS::new();
+        ecs.add_system(render);
+        ecs.register_system::<Physics>();
+        ecs.add_system(input);
+        ecs.add_system(movement);
+
+        assert_eq!(order(&mut ecs, Update), Ok(vec!["render", "Physics", "input", "movement"]));
+    }
+
+    #[test]
+    fn constraints_move_systems_around_their_targets()
+    {
+        let mut ecs = ECS::new();
+        ecs.add_system(render).after::<Physics>();
+        ecs.add_system(movement).after_system(input);
+        ecs.register_system::<Physics>().after_system(movement);
+        ecs.add_system(input);
+
+        assert_eq!(order(&mut ecs, Update), Ok(vec!["input", "movement", "Physics", "render"]));
+
+        let mut ecs = ECS::new();
+        ecs.add_system(render);
+        ecs.add_system(movement);
+        ecs.register_system::<Physics>().before_system(render);
+        ecs.add_system(input).before_system(movement).before::<Physics>();
+
+        // Once `input` ran, `movement` is the earliest registered system that is ready.
+        assert_eq!(order(&mut ecs, Update), Ok(vec!["input", "movement", "Physics", "render"]));
+    }
+
+    #[test]
+    fn constraints_on_missing_systems_are_ignored()
+    {
+        let mut ecs = ECS::new();
+        ecs.add_system(movement).after::<Physics>();
+        ecs.add_system(input).before_system(render);
+
+        assert_eq!(order(&mut ecs, Update), Ok(vec!["movement", "input"]));
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_systems()
+    {
+        let mut ecs = ECS::new();
+        ecs.add_system(input);
+        ecs.add_system(movement).before::<Physics>();
+        ecs.register_system::<Physics>().before_system(render);
+        ecs.add_system(render).before_system(movement);
+
+        let error = order(&mut ecs, Update).unwrap_err();
+        let ScheduleError::Cycle(cycle) = &error;
+        let cycle: Vec<_> = cycle.iter().map(|name| name.rsplit("::").next().unwrap()).collect();
+        assert_eq!(cycle, ["Physics", "render", "movement", "Physics"]);
+        assert!(error.to_string().starts_with("System order contains a cycle: "));
+        assert_eq!(ScheduleError::Cycle(vec!["a", "b", "a"]).to_string(), "System order contains a cycle: a -> b -> a");
+    }
+
+    #[test]
+    #[should_panic(expected = "System order contains a cycle")]
+    fn running_a_cycle_panics()
+    {
+        let mut ecs = ECS::new();
+        ecs.add_system(input).after_system(movement);
+        ecs.add_system(movement).after_system(input);
+
+        ecs.update();
+    }
+
+    #[test]
+    fn conflicting_systems_never_share_a_stage()
+    {
+        let mut ecs = ECS::new();
+        ecs.set_executor(ExecutorKind::MultiThreaded);
+        ecs.insert_resource(Time(0));
+        ecs.spawn((A(0), B));
+
+        ecs.add_system(write_a);
+        ecs.add_system(write_b);
+        ecs.add_system(read_time);
+        ecs.add_system(read_a);
+        ecs.add_system(write_time);
+        ecs.add_system(read_a_and_b);
+
+        let schedule = ecs.schedule_mut(Update);
+        let order = schedule.sort().unwrap();
+        for stage in &order.stages
+        {
+            let systems = &order.systems[stage.clone()];
+            for (position, &index) in systems.iter().enumerate()
+            {
+                for &other in &systems[position + 1..]
+                {
+                    let (SystemKind::Function(system), SystemKind::Function(other_system)) = (&schedule.systems[index].kind, &schedule.systems[other].kind) else
+                    {
+                        panic!("`System` impls have to run alone");
+                    };
+                    assert!(system.access().is_compatible(other_system.access()), "{} and {} share a stage", system.name(), other_system.name());
+                }
+            }
+        }
+
+        // write_a, write_b and read_time run together, then read_a, write_time and read_a_and_b.
+        assert_eq!(order.stages, vec![0..3, 3..6]);
+
+        ecs.update();
+        ecs.update();
+        assert_eq!(ecs.resource::<Time>().map(|time| time.0), Some(2));
+        assert_eq!(ecs.storage().query::<(&A,)>().map(|(_, a)| a.0).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn commands_are_applied_before_later_systems_run()
+    {
+        let run = |executor|
+        {
+            let mut ecs = ECS::new();
+            ecs.set_executor(executor);
+            ecs.insert_resource(Time(0));
+            ecs.insert_resource(SeenA(0));
+            ecs.add_system(spawn_a);
+            ecs.add_system(read_time);
+            ecs.add_system(count_a);
+
+            let mut seen = Vec::new();
+            for _ in 0..2
+            {
+                ecs.update();
+                seen.push(ecs.resource::<SeenA>().unwrap().0);
+            }
+
+            (ecs.schedule_mut(Update).sort().unwrap().stages, seen, ecs.storage().change_tick())
+        };
+
+        // `spawn_a` ends its stage, although `read_time` and `count_a` do not conflict with it, so
+        // `count_a` sees the entity spawned in the same update and the ticks match a single-threaded run.
+        let (stages, seen, change_tick) = run(ExecutorKind::MultiThreaded);
+        assert_eq!(stages, vec![0..1, 1..3]);
+        assert_eq!(seen, vec![1, 2]);
+        assert_eq!((seen, change_tick), { let (_, seen, change_tick) = run(ExecutorKind::SingleThreaded); (seen, change_tick) });
+    }
+
+    struct PreUpdate;
+    struct Cleanup;
+
+    impl ScheduleLabel for PreUpdate {}
+    impl ScheduleLabel for Cleanup {}
+
+    // The phases `Phases` ran, in order.
+    struct PhaseLog(Vec<&'static str>);
+
+    struct Phases;
+
+    impl Phases
+    {
+        fn log(storage: &mut ECSStorage, phase: &'static str)
+        {
+            storage.resource_mut::<PhaseLog>().unwrap().0.push(phase);
+        }
+    }
+
+    impl System for Phases
+    {
+        fn new() -> Self
+        {
+            Self
+        }
+
+        fn start(&self, storage: &mut ECSStorage)
+        {
+            Self::log(storage, "start");
+        }
+
+        fn update(&self, storage: &mut ECSStorage)
+        {
+            Self::log(storage, "update");
+        }
+
+        fn fixed_update(&self, storage: &mut ECSStorage)
+        {
+            Self::log(storage, "fixed_update");
+        }
+
+        fn render(&self, storage: &mut ECSStorage)
+        {
+            Self::log(storage, "render");
+        }
+    }
+
+    #[test]
+    fn system_impls_run_in_every_built_in_schedule()
+    {
+        let mut ecs = ECS::new();
+        ecs.insert_resource(PhaseLog(Vec::new()));
+        ecs.register_system::<Phases>();
+
+        ecs.start();
+        ecs.update();
+        ecs.fixed_update();
+        ecs.render();
+        ecs.run_schedule(Update);
+        ecs.run_schedule(PreUpdate);
+
+        assert_eq!(ecs.resource::<PhaseLog>().unwrap().0, ["start", "update", "fixed_update", "render", "update"]);
+        for order in [ecs.system_order(Start), ecs.system_order(FixedUpdate), ecs.system_order(Render)]
+        {
+            assert_eq!(order.map(|order| order.len()), Ok(1));
+        }
+    }
+
+    #[test]
+    fn user_schedules_only_run_on_request()
+    {
+        let mut ecs = ECS::new();
+        ecs.insert_resource(Time(0));
+        ecs.add_system_to(PreUpdate, write_time);
+
+        ecs.update();
+        assert_eq!(ecs.resource::<Time>().map(|time| time.0), Some(0));
+
+        ecs.run_schedule(PreUpdate);
+        ecs.run_schedule(PreUpdate);
+        assert_eq!(ecs.resource::<Time>().map(|time| time.0), Some(2));
+        assert_eq!(order(&mut ecs, PreUpdate), Ok(vec!["write_time"]));
+    }
+
+    #[test]
+    fn unknown_schedules_only_apply_commands()
+    {
+        let mut ecs = ECS::new();
+        ecs.commands().insert_resource(Time(3));
+
+        ecs.run_schedule(Cleanup);
+
+        assert_eq!(ecs.resource::<Time>().map(|time| time.0), Some(3));
+        assert_eq!(ecs.system_order(Cleanup), Ok(Vec::new()));
+    }
+}
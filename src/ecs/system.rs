@@ -1,13 +1,117 @@
-use std::any::Any;
+use std::{cell::RefCell, rc::Rc};
 
-use super::ECSStorage;
+use super::{change_detection::Tick, ECSStorage};
+
+pub mod param;
+pub mod function;
+
+use param::SystemAccess;
 
 pub trait System
 {
     fn new() -> Self where Self: Sized;
     
-    fn start       (&self, ecs: &mut ECSStorage) { }
-    fn update      (&self, ecs: &mut ECSStorage) { }
-    fn fixed_update(&self, ecs: &mut ECSStorage) { }
-    fn render      (&self, ecs: &mut ECSStorage) { }
-}
\ No newline at end of file
+    fn start       (&self, _ecs: &mut ECSStorage) { }
+    fn update      (&self, _ecs: &mut ECSStorage) { }
+    fn fixed_update(&self, _ecs: &mut ECSStorage) { }
+    fn render      (&self, _ecs: &mut ECSStorage) { }
+}
+
+// Object safe interface of function systems, see `function::IntoSystem`.
+pub trait RunSystem: Send
+{
+    fn name(&self) -> &str;
+
+    // Sets up the parameter state; called once before the first `run`.
+    fn initialize(&mut self, storage: &mut ECSStorage);
+
+    // The components and resources the parameters touch. Empty before `initialize`.
+    fn access(&self) -> &SystemAccess;
+
+    // Runs the system, stamping its writes with `change_tick`, but leaves its deferred work for `apply_deferred`.
+    // Safety: nothing may mutate the storage meanwhile, and systems running at the same time must have
+    // compatible accesses, see `SystemAccess::is_compatible`.
+    unsafe fn run_unsafe(&mut self, storage: &ECSStorage, change_tick: Tick);
+
+    // Applies the work the parameters deferred, such as commands.
+    fn apply_deferred(&mut self, storage: &mut ECSStorage);
+
+    // Runs the system alone, then flushes its commands and advances the world tick so later
+    // changes are newer than this run.
+    fn run(&mut self, storage: &mut ECSStorage)
+    {
+        // Safety: the storage is mutably borrowed for the whole run.
+        unsafe { self.run_unsafe(storage, storage.change_tick()) };
+        self.apply_deferred(storage);
+
+        storage.increment_change_tick();
+    }
+}
+
+// Runs `system` against its own last-run tick, then flushes its commands and advances the
+// world tick so later changes are newer than this run. The storage's `last_change_tick` is
+// restored afterwards.
+fn run_tracked(last_run: &mut Tick, storage: &mut ECSStorage, system: impl FnOnce(&mut ECSStorage))
+{
+    let last_change_tick = storage.last_change_tick();
+    storage.set_last_change_tick(*last_run);
+
+    system(storage);
+    storage.apply_commands();
+
+    *last_run = storage.increment_change_tick();
+    storage.set_last_change_tick(last_change_tick);
+}
+
+// One of the methods of `System`, run by the matching built-in schedule.
+pub type Phase = fn(&dyn System, &mut ECSStorage);
+
+pub struct RegisteredSystem
+{
+    system: Box<dyn System>,
+    last_run: Tick,
+}
+
+impl RegisteredSystem
+{
+    pub fn new(system: Box<dyn System>) -> Self
+    {
+        Self
+        {
+            system,
+            last_run: 0,
+        }
+    }
+
+    // Runs one phase of the system, see `run_tracked`.
+    pub fn run(&mut self, storage: &mut ECSStorage, phase: impl FnOnce(&dyn System, &mut ECSStorage))
+    {
+        let system = self.system.as_ref();
+        run_tracked(&mut self.last_run, storage, |storage| phase(system, storage));
+    }
+}
+
+// One phase of a `System` impl in a schedule. All phases share the impl and its last run, so change
+// detection in one phase sees the changes made since any phase of the impl last ran.
+pub struct PhaseSystem
+{
+    system: Rc<RefCell<RegisteredSystem>>,
+    phase: Phase,
+}
+
+impl PhaseSystem
+{
+    pub fn new(system: Rc<RefCell<RegisteredSystem>>, phase: Phase) -> Self
+    {
+        Self
+        {
+            system,
+            phase,
+        }
+    }
+
+    pub fn run(&mut self, storage: &mut ECSStorage)
+    {
+        self.system.borrow_mut().run(storage, self.phase);
+    }
+}
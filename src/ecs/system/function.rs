@@ -0,0 +1,147 @@
+use std::{any::type_name, marker::PhantomData};
+
+use crate::ecs::{change_detection::{SystemTicks, Tick}, ECSStorage};
+
+use super::{param::{SystemAccess, SystemParam, SystemParamItem}, RunSystem};
+
+// Implemented for functions whose arguments are all `SystemParam`s, e.g.
+// `fn movement(query: Query<(&mut Pos, &Vel)>, time: Res<Time>)`.
+// `Marker` is the function pointer type and only keeps the impls for different arities apart.
+pub trait SystemParamFunction<Marker>: Send + Sync + 'static
+{
+    type Param: SystemParam;
+
+    fn run(&mut self, param: SystemParamItem<'_, '_, Self::Param>);
+}
+
+pub trait IntoSystem<Marker>
+{
+    type System: RunSystem + 'static;
+
+    fn into_system(self) -> Self::System;
+}
+
+impl<Marker: 'static, F: SystemParamFunction<Marker>> IntoSystem<Marker> for F
+{
+    type System = FunctionSystem<Marker, F>;
+
+    fn into_system(self) -> Self::System
+    {
+        FunctionSystem::new(self)
+    }
+}
+
+pub struct FunctionSystem<Marker, F: SystemParamFunction<Marker>>
+{
+    func: F,
+    state: Option<<F::Param as SystemParam>::State>,
+    access: SystemAccess,
+    last_run: Tick,
+    _marker: PhantomData<fn() -> Marker>,
+}
+
+impl<Marker, F: SystemParamFunction<Marker>> FunctionSystem<Marker, F>
+{
+    pub fn new(func: F) -> Self
+    {
+        Self
+        {
+            func,
+            state: None,
+            access: SystemAccess::new(type_name::<F>()),
+            last_run: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Marker, F: SystemParamFunction<Marker>> RunSystem for FunctionSystem<Marker, F>
+{
+    fn name(&self) -> &str
+    {
+        type_name::<F>()
+    }
+
+    fn initialize(&mut self, storage: &mut ECSStorage)
+    {
+        self.state = Some(F::Param::init_state(storage, &mut self.access));
+    }
+
+    fn access(&self) -> &SystemAccess
+    {
+        &self.access
+    }
+
+    unsafe fn run_unsafe(&mut self, storage: &ECSStorage, change_tick: Tick)
+    {
+        let state = self.state.as_mut().expect("System ran before it was initialized");
+        let ticks = SystemTicks
+        {
+            last_change_tick: self.last_run,
+            change_tick,
+        };
+
+        // Safety: the caller rules out other access to what the parameters registered, and
+        // `initialize` checked that the parameters do not alias each other.
+        let param = F::Param::get_param(state, storage, ticks);
+        self.func.run(param);
+
+        self.last_run = change_tick;
+    }
+
+    fn apply_deferred(&mut self, storage: &mut ECSStorage)
+    {
+        if let Some(state) = &mut self.state
+        {
+            F::Param::apply(state, storage);
+        }
+
+        storage.apply_commands();
+    }
+}
+
+macro_rules! impl_system_param_function
+{
+    ($($param:ident),*) =>
+    {
+        // Requiring both `FnMut` impls lets the compiler relate the parameter types to their
+        // items, which only differ in lifetimes.
+        #[allow(non_snake_case, clippy::too_many_arguments)]
+        impl<Func, $($param: SystemParam),*> SystemParamFunction<fn($($param,)*)> for Func
+        where
+            Func: Send + Sync + 'static,
+            for<'a> &'a mut Func: FnMut($($param),*) + FnMut($(SystemParamItem<$param>),*),
+        {
+            type Param = ($($param,)*);
+
+            fn run(&mut self, param: SystemParamItem<'_, '_, Self::Param>)
+            {
+                fn call_inner<$($param),*>(mut func: impl FnMut($($param),*), $($param: $param),*)
+                {
+                    func($($param),*)
+                }
+
+                let ($($param,)*) = param;
+                call_inner(self, $($param),*)
+            }
+        }
+    };
+}
+
+impl_system_param_function!();
+impl_system_param_function!(P1);
+impl_system_param_function!(P1, P2);
+impl_system_param_function!(P1, P2, P3);
+impl_system_param_function!(P1, P2, P3, P4);
+impl_system_param_function!(P1, P2, P3, P4, P5);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13, P14);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13, P14, P15);
+impl_system_param_function!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13, P14, P15, P16);
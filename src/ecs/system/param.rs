@@ -0,0 +1,483 @@
+use std::{any::type_name, marker::PhantomData, ops::{Deref, DerefMut}};
+
+use crate::ecs::{change_detection::SystemTicks, command::Commands, event::{EventCursor, Events}, query::{filter::QueryFilter, ComponentAccess, QueryData, QueryIter, QueryTuple, ReadOnlyQueryData}, ECSStorage};
+
+// Everything a function system's parameters read or write, collected when the system is initialized.
+// Resources are tracked with the same `ComponentAccess` records as components.
+pub struct SystemAccess
+{
+    system_name: &'static str,
+    components: Vec<ComponentAccess>,
+    resources: Vec<ComponentAccess>,
+    deferred: bool,
+}
+
+impl SystemAccess
+{
+    pub fn new(system_name: &'static str) -> Self
+    {
+        Self
+        {
+            system_name,
+            components: Vec::new(),
+            resources: Vec::new(),
+            deferred: false,
+        }
+    }
+
+    // The accesses of one parameter; panics if they alias the accesses of an earlier parameter.
+    pub fn add_components(&mut self, accesses: impl IntoIterator<Item = ComponentAccess>)
+    {
+        let accesses: Vec<_> = accesses.into_iter().collect();
+        Self::check(self.system_name, &self.components, &accesses);
+        self.components.extend(accesses);
+    }
+
+    pub fn add_resource(&mut self, access: ComponentAccess)
+    {
+        Self::check(self.system_name, &self.resources, &[access]);
+        self.resources.push(access);
+    }
+
+    // Marks that a parameter defers work, e.g. commands, until the system returns.
+    pub fn add_deferred(&mut self)
+    {
+        self.deferred = true;
+    }
+
+    pub fn has_deferred(&self) -> bool
+    {
+        self.deferred
+    }
+
+    pub fn components(&self) -> &[ComponentAccess]
+    {
+        &self.components
+    }
+
+    pub fn resources(&self) -> &[ComponentAccess]
+    {
+        &self.resources
+    }
+
+    // Whether two systems can run at the same time.
+    pub fn is_compatible(&self, other: &SystemAccess) -> bool
+    {
+        let conflicts = |accesses: &[ComponentAccess], others: &[ComponentAccess]| accesses.iter().any(|access| others.iter().any(|other| access.conflicts_with(other)));
+        !conflicts(&self.components, &other.components) && !conflicts(&self.resources, &other.resources)
+    }
+
+    fn check(system_name: &str, existing: &[ComponentAccess], accesses: &[ComponentAccess])
+    {
+        for access in accesses
+        {
+            if existing.iter().any(|other| access.conflicts_with(other))
+            {
+                panic!("System {} accesses {} mutably more than once or both mutably and immutably", system_name, access.type_name);
+            }
+        }
+    }
+}
+
+// Something a function system can take as an argument, extracted from the storage on every run.
+// Systems may run on other threads, so the impls require `Send`/`Sync` of what they hand out.
+pub trait SystemParam
+{
+    // Kept by the system between runs, e.g. the cursor of an `EventReader`.
+    type State: Send + Sync + 'static;
+
+    type Item<'w, 's>;
+
+    fn init_state(storage: &mut ECSStorage, access: &mut SystemAccess) -> Self::State;
+
+    // Safety: nothing may mutate the storage while the item is alive, and nothing but the other
+    // parameters registered in the same `SystemAccess` may access what this parameter registered.
+    unsafe fn get_param<'w, 's>(state: &'s mut Self::State, storage: &'w ECSStorage, ticks: SystemTicks) -> Self::Item<'w, 's>;
+
+    // Applies work the parameter deferred while the system ran, e.g. recorded commands.
+    fn apply(_state: &mut Self::State, _storage: &mut ECSStorage) {}
+}
+
+pub type SystemParamItem<'w, 's, P> = <P as SystemParam>::Item<'w, 's>;
+
+pub struct Query<'w, Q, F = ()>
+{
+    storage: &'w ECSStorage,
+    ticks: SystemTicks,
+    _marker: PhantomData<fn() -> (Q, F)>,
+}
+
+impl<Q: QueryData, F: for<'a> QueryFilter<'a>> Query<'_, Q, F>
+{
+    pub fn iter(&self) -> QueryIter<'_, Q::Fetch<'_>, F> where Q: ReadOnlyQueryData
+    {
+        // Safety: `Q` is read-only.
+        unsafe { QueryIter::with_ticks(self.storage, self.ticks) }
+    }
+
+    pub fn iter_mut(&mut self) -> QueryIter<'_, Q::Fetch<'_>, F>
+    {
+        // Safety: `SystemAccess` ruled out aliasing with the other parameters and concurrently running
+        // systems, and `&mut self` with other iterators of this query.
+        unsafe { QueryIter::with_ticks(self.storage, self.ticks) }
+    }
+}
+
+impl<'a, Q: ReadOnlyQueryData, F: for<'f> QueryFilter<'f>> IntoIterator for &'a Query<'_, Q, F>
+{
+    type Item = <Q::Fetch<'a> as QueryTuple<'a>>::Item;
+    type IntoIter = QueryIter<'a, Q::Fetch<'a>, F>;
+
+    fn into_iter(self) -> Self::IntoIter
+    {
+        self.iter()
+    }
+}
+
+impl<'a, Q: QueryData, F: for<'f> QueryFilter<'f>> IntoIterator for &'a mut Query<'_, Q, F>
+{
+    type Item = <Q::Fetch<'a> as QueryTuple<'a>>::Item;
+    type IntoIter = QueryIter<'a, Q::Fetch<'a>, F>;
+
+    fn into_iter(self) -> Self::IntoIter
+    {
+        self.iter_mut()
+    }
+}
+
+impl<Q: QueryData + 'static, F: for<'a> QueryFilter<'a> + 'static> SystemParam for Query<'_, Q, F>
+{
+    type State = ();
+    type Item<'w, 's> = Query<'w, Q, F>;
+
+    fn init_state(_: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+    {
+        access.add_components(Q::access().into_iter().chain(<F as QueryFilter<'static>>::access()));
+    }
+
+    unsafe fn get_param<'w, 's>(_: &'s mut Self::State, storage: &'w ECSStorage, ticks: SystemTicks) -> Self::Item<'w, 's>
+    {
+        Query
+        {
+            storage,
+            ticks,
+            _marker: PhantomData,
+        }
+    }
+}
+
+fn missing_resource<R>() -> !
+{
+    panic!("Resource {} does not exist", type_name::<R>())
+}
+
+pub struct Res<'w, R>
+{
+    value: &'w R,
+}
+
+impl<R> Deref for Res<'_, R>
+{
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target
+    {
+        self.value
+    }
+}
+
+impl<R: Sync + 'static> SystemParam for Res<'_, R>
+{
+    type State = ();
+    type Item<'w, 's> = Res<'w, R>;
+
+    fn init_state(_: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+    {
+        access.add_resource(ComponentAccess::of::<R>(false));
+    }
+
+    unsafe fn get_param<'w, 's>(_: &'s mut Self::State, storage: &'w ECSStorage, _: SystemTicks) -> Self::Item<'w, 's>
+    {
+        Res
+        {
+            value: storage.resource::<R>().unwrap_or_else(|| missing_resource::<R>()),
+        }
+    }
+}
+
+pub struct ResMut<'w, R>
+{
+    value: &'w mut R,
+}
+
+impl<R> Deref for ResMut<'_, R>
+{
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target
+    {
+        self.value
+    }
+}
+
+impl<R> DerefMut for ResMut<'_, R>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target
+    {
+        self.value
+    }
+}
+
+impl<R: Send + 'static> SystemParam for ResMut<'_, R>
+{
+    type State = ();
+    type Item<'w, 's> = ResMut<'w, R>;
+
+    fn init_state(_: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+    {
+        access.add_resource(ComponentAccess::of::<R>(true));
+    }
+
+    unsafe fn get_param<'w, 's>(_: &'s mut Self::State, storage: &'w ECSStorage, _: SystemTicks) -> Self::Item<'w, 's>
+    {
+        ResMut
+        {
+            value: storage.resource_unchecked_mut::<R>().unwrap_or_else(|| missing_resource::<R>()),
+        }
+    }
+}
+
+// Commands are deferred until the system returns, so they need no access. Each system records
+// into its own queue, so systems running at the same time do not interleave their commands.
+impl SystemParam for Commands
+{
+    type State = Commands;
+    type Item<'w, 's> = Commands;
+
+    fn init_state(storage: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+    {
+        access.add_deferred();
+        Commands::new(storage.entity_uuid_counter.clone())
+    }
+
+    unsafe fn get_param<'w, 's>(state: &'s mut Self::State, _: &'w ECSStorage, _: SystemTicks) -> Self::Item<'w, 's>
+    {
+        state.clone()
+    }
+
+    fn apply(state: &mut Self::State, storage: &mut ECSStorage)
+    {
+        for command in state.take()
+        {
+            command(storage);
+        }
+    }
+}
+
+// Reads the `Events<E>` resource, each system with its own cursor.
+pub struct EventReader<'w, 's, E>
+{
+    cursor: &'s mut EventCursor<E>,
+    events: &'w Events<E>,
+}
+
+impl<'w, E> EventReader<'w, '_, E>
+{
+    pub fn read(&mut self) -> impl Iterator<Item = &'w E> + '_
+    {
+        self.cursor.read(self.events)
+    }
+}
+
+impl<E: Sync + 'static> SystemParam for EventReader<'_, '_, E>
+{
+    type State = EventCursor<E>;
+    type Item<'w, 's> = EventReader<'w, 's, E>;
+
+    fn init_state(storage: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+    {
+        storage.add_event::<E>();
+        access.add_resource(ComponentAccess::of::<Events<E>>(false));
+        EventCursor::new()
+    }
+
+    unsafe fn get_param<'w, 's>(state: &'s mut Self::State, storage: &'w ECSStorage, _: SystemTicks) -> Self::Item<'w, 's>
+    {
+        EventReader
+        {
+            cursor: state,
+            events: storage.resource::<Events<E>>().unwrap_or_else(|| missing_resource::<Events<E>>()),
+        }
+    }
+}
+
+pub struct EventWriter<'w, E>
+{
+    events: &'w mut Events<E>,
+}
+
+impl<E> EventWriter<'_, E>
+{
+    pub fn send(&mut self, event: E)
+    {
+        self.events.send(event);
+    }
+}
+
+impl<E: Send + 'static> SystemParam for EventWriter<'_, E>
+{
+    type State = ();
+    type Item<'w, 's> = EventWriter<'w, E>;
+
+    fn init_state(storage: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+    {
+        storage.add_event::<E>();
+        access.add_resource(ComponentAccess::of::<Events<E>>(true));
+    }
+
+    unsafe fn get_param<'w, 's>(_: &'s mut Self::State, storage: &'w ECSStorage, _: SystemTicks) -> Self::Item<'w, 's>
+    {
+        EventWriter
+        {
+            events: storage.resource_unchecked_mut::<Events<E>>().unwrap_or_else(|| missing_resource::<Events<E>>()),
+        }
+    }
+}
+
+macro_rules! impl_system_param
+{
+    ($($name:ident),*) =>
+    {
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        impl<$($name: SystemParam),*> SystemParam for ($($name,)*)
+        {
+            type State = ($($name::State,)*);
+            type Item<'w, 's> = ($($name::Item<'w, 's>,)*);
+
+            fn init_state(storage: &mut ECSStorage, access: &mut SystemAccess) -> Self::State
+            {
+                ($($name::init_state(storage, access),)*)
+            }
+
+            unsafe fn get_param<'w, 's>(state: &'s mut Self::State, storage: &'w ECSStorage, ticks: SystemTicks) -> Self::Item<'w, 's>
+            {
+                let ($($name,)*) = state;
+                ($($name::get_param($name, storage, ticks),)*)
+            }
+
+            fn apply(state: &mut Self::State, storage: &mut ECSStorage)
+            {
+                let ($($name,)*) = state;
+                $( $name::apply($name, storage); )*
+            }
+        }
+    };
+}
+
+impl_system_param!();
+impl_system_param!(P1);
+impl_system_param!(P1, P2);
+impl_system_param!(P1, P2, P3);
+impl_system_param!(P1, P2, P3, P4);
+impl_system_param!(P1, P2, P3, P4, P5);
+impl_system_param!(P1, P2, P3, P4, P5, P6);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13, P14);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13, P14, P15);
+impl_system_param!(P1, P2, P3, P4, P5, P6, P7, P8, P9, P10, P11, P12, P13, P14, P15, P16);
+
+#[cfg(test)]
+mod tests
+{
+    use crate::ecs::{command::Commands, query::filter::{Changed, With}, ECS};
+
+    use super::{EventReader, EventWriter, Query, Res, ResMut};
+
+    struct Time(u32);
+    struct Hit(u32);
+    struct HitsSeen(Vec<u32>);
+
+    fn writes_a_twice(_: Query<(&mut u32,)>, _: Query<(&mut u32, &u64)>) {}
+    fn writes_and_reads_a(_: Query<(&u64,)>, _: Query<(&mut u32,)>, _: Query<(&u32,)>) {}
+    fn filters_on_written_a(_: Query<(&mut u32,)>, _: Query<(&u64,), (Changed<u32>,)>) {}
+    fn reads_and_writes_time(_: Res<Time>, _: ResMut<Time>) {}
+    fn disjoint(_: Query<(&mut u32,), (With<u64>,)>, _: Query<(&mut u64,)>, _: Res<Time>, _: Res<Time>) {}
+
+    #[test]
+    #[should_panic(expected = "writes_a_twice accesses")]
+    fn rejects_two_parameters_writing_a_component()
+    {
+        ECS::new().add_system(writes_a_twice);
+    }
+
+    #[test]
+    #[should_panic(expected = "writes_and_reads_a accesses")]
+    fn rejects_parameters_writing_and_reading_a_component()
+    {
+        ECS::new().add_system(writes_and_reads_a);
+    }
+
+    #[test]
+    #[should_panic(expected = "filters_on_written_a accesses")]
+    fn rejects_change_filters_on_a_component_another_parameter_writes()
+    {
+        ECS::new().add_system(filters_on_written_a);
+    }
+
+    #[test]
+    #[should_panic(expected = "reads_and_writes_time accesses")]
+    fn rejects_parameters_reading_and_writing_a_resource()
+    {
+        ECS::new().add_system(reads_and_writes_time);
+    }
+
+    #[test]
+    fn allows_disjoint_parameters()
+    {
+        let mut ecs = ECS::new();
+        ecs.insert_resource(Time(0));
+        ecs.spawn((0u32, 0u64));
+        ecs.add_system(disjoint);
+        ecs.update();
+    }
+
+    fn movement(mut query: Query<(&mut u32, &u64)>, time: Res<Time>, mut hits: EventWriter<Hit>, mut commands: Commands)
+    {
+        for (entity, mut position, velocity) in query.iter_mut()
+        {
+            *position += *velocity as u32 * time.0;
+            hits.send(Hit(*position));
+            commands.insert(entity, true);
+        }
+    }
+
+    fn count_hits(mut hits: EventReader<Hit>, mut seen: ResMut<HitsSeen>)
+    {
+        seen.0.extend(hits.read().map(|hit| hit.0));
+    }
+
+    #[test]
+    fn function_systems_get_their_parameters()
+    {
+        let mut ecs = ECS::new();
+        ecs.insert_resource(Time(2));
+        ecs.insert_resource(HitsSeen(Vec::new()));
+        let entity = ecs.spawn((1u32, 3u64));
+        ecs.add_system(movement);
+        ecs.add_system(count_hits);
+
+        ecs.update();
+        assert_eq!(ecs.get_component::<u32>(entity), Some(&7));
+        assert_eq!(ecs.get_component::<bool>(entity), Some(&true));
+        assert_eq!(ecs.resource::<HitsSeen>().map(|seen| seen.0.clone()), Some(vec![7]));
+
+        ecs.update();
+        assert_eq!(ecs.resource::<HitsSeen>().map(|seen| seen.0.clone()), Some(vec![7, 13]));
+    }
+}
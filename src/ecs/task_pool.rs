@@ -0,0 +1,198 @@
+use std::{any::Any, mem, num::NonZeroUsize, panic::{self, AssertUnwindSafe}, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc, Condvar, Mutex}, thread::{self, JoinHandle}};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Worker threads started once and kept until the pool is dropped. Workers and the calling
+// thread pull the next task from a shared counter, so uneven tasks balance out.
+pub struct TaskPool
+{
+    // Dropped first on `drop`, which tells the workers to exit.
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+// Counts the jobs of one `for_each` that are still running, and keeps the first panic among them.
+struct Pending
+{
+    state: Mutex<(usize, Option<Box<dyn Any + Send>>)>,
+    done: Condvar,
+}
+
+impl Pending
+{
+    fn finish(&self, result: thread::Result<()>)
+    {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        state.0 -= 1;
+        if let Err(payload) = result
+        {
+            state.1.get_or_insert(payload);
+        }
+
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> Option<Box<dyn Any + Send>>
+    {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        while state.0 > 0
+        {
+            state = self.done.wait(state).unwrap_or_else(|error| error.into_inner());
+        }
+
+        state.1.take()
+    }
+}
+
+impl TaskPool
+{
+    // One thread per available core, the calling thread included.
+    pub fn new() -> Self
+    {
+        Self::with_threads(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    pub fn with_threads(threads: usize) -> Self
+    {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (1..threads.max(1)).map(|index|
+        {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("task-pool-{}", index))
+                .spawn(move ||
+                {
+                    // The lock is released before the job runs, so the other workers keep pulling.
+                    while let Ok(job) = { let receiver = receiver.lock().unwrap(); receiver.recv() }
+                    {
+                        job();
+                    }
+                })
+                .expect("Failed to spawn a task pool worker")
+        }).collect();
+
+        Self
+        {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    // The number of threads tasks run on, the calling thread included.
+    pub fn threads(&self) -> usize
+    {
+        self.workers.len() + 1
+    }
+
+    // Calls `f` with every task, on up to `threads` threads including the calling one, and returns
+    // once all of them are done. A panic in `f` is resumed on the calling thread.
+    pub fn for_each<T>(&self, tasks: &[T], f: impl Fn(&T) + Sync) where T: Sync
+    {
+        let next = AtomicUsize::new(0);
+        let work = ||
+        {
+            while let Some(task) = tasks.get(next.fetch_add(1, Ordering::Relaxed))
+            {
+                f(task);
+            }
+        };
+
+        let helpers = self.workers.len().min(tasks.len().saturating_sub(1));
+        // Shared rather than borrowed, since a job still touches it after this call may have returned.
+        let pending = Arc::new(Pending
+        {
+            state: Mutex::new((helpers, None)),
+            done: Condvar::new(),
+        });
+
+        for _ in 0..helpers
+        {
+            let job_pending = pending.clone();
+            let work = &work;
+            let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || job_pending.finish(panic::catch_unwind(AssertUnwindSafe(work))));
+            // Safety: besides `pending`, the job only borrows locals of this call, and `pending.wait`
+            // below does not return before every job is done with them, even if `work` panics here.
+            let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + '_>, Job>(job) };
+            if let Err(mpsc::SendError(job)) = self.sender.as_ref().unwrap().send(job)
+            {
+                job();
+            }
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(&work));
+        let worker_panic = pending.wait();
+
+        if let Some(payload) = result.err().or(worker_panic)
+        {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+impl Default for TaskPool
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Drop for TaskPool
+{
+    fn drop(&mut self)
+    {
+        self.sender = None;
+        for worker in self.workers.drain(..)
+        {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::{collections::HashSet, panic::{self, AssertUnwindSafe}, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, thread::{self, ThreadId}};
+
+    use super::TaskPool;
+
+    #[test]
+    fn workers_are_reused_across_calls()
+    {
+        let pool = TaskPool::with_threads(3);
+        let tasks: Vec<usize> = (0..64).collect();
+        let threads = Mutex::new(HashSet::<ThreadId>::new());
+        let sum = AtomicUsize::new(0);
+
+        for _ in 0..10
+        {
+            pool.for_each(&tasks, |task|
+            {
+                threads.lock().unwrap().insert(thread::current().id());
+                sum.fetch_add(*task, Ordering::Relaxed);
+                thread::yield_now();
+            });
+        }
+
+        assert_eq!(pool.threads(), 3);
+        assert_eq!(sum.into_inner(), 10 * (0..64).sum::<usize>());
+        // Ten calls spawning their own threads would have seen up to twenty different workers.
+        assert!(threads.into_inner().unwrap().len() <= 3);
+    }
+
+    #[test]
+    fn panics_reach_the_caller_and_the_pool_stays_usable()
+    {
+        let pool = TaskPool::with_threads(4);
+        let tasks: Vec<usize> = (0..16).collect();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.for_each(&tasks, |&task| assert_ne!(task, 7))));
+        assert!(result.is_err());
+
+        let count = AtomicUsize::new(0);
+        pool.for_each(&tasks, |_| { count.fetch_add(1, Ordering::Relaxed); });
+        assert_eq!(count.into_inner(), 16);
+    }
+}
@@ -0,0 +1,5 @@
+// Safety requirements are written as plain comments on the unsafe functions, like all other docs here.
+#![allow(clippy::missing_safety_doc)]
+
+pub mod data_structures;
+pub mod ecs;
@@ -1,13 +1,16 @@
+use std::collections::HashMap;
+
 use data_structures::type_erased_vec::TypeErasedVec;
-use ecs::system::System;
+use ecs::component::registry::StorageKind;
 use serde::Serialize;
 
-mod ecs;
-mod data_structures;
+use ::ecs::{data_structures, ecs};
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct A { x: i32 }
+#[derive(Default)]
 pub struct B { y: f32 }
+#[derive(Default)]
 pub struct C { z: f32 }
 
 pub struct MySystem;
@@ -19,12 +22,12 @@ impl ecs::system::System for MySystem
     }
 
     fn start(&self, ecs: &mut ecs::ECSStorage) {
-        ecs.iter_components_mut::<A>().for_each(|(entity, a)| {
+        ecs.iter_components_mut::<A>().for_each(|(_, mut a)| {
             a.x = 42;
         });
 
-        ecs.iter_components_mut::<B>().for_each(|(entity, b)| {
-            b.y = entity as f32;
+        ecs.iter_components_mut::<B>().for_each(|(entity, mut b)| {
+            b.y = entity.index() as f32;
         });
     }
 
@@ -41,12 +44,12 @@ impl ecs::system::System for MySystem
             println!("Entity {} has A: {}", entity, a.x);
         });
         
-        ecs.query_mut::<(&A, &mut B)>().for_each(|(entity, a, b)| {
+        ecs.query_mut::<(&A, &mut B)>().for_each(|(entity, a, mut b)| {
             b.y = 42.0;
             println!("Entity {} has A: {} and B: {}", entity, a.x, b.y);
         });
 
-        ecs.query_mut::<(&A, &mut B, &mut C)>().for_each(|(entity, a, b, c)| {
+        ecs.query_mut::<(&A, &mut B, &mut C)>().for_each(|(entity, a, mut b, mut c)| {
             b.y = 42.0;
             c.z = 42.0;
             println!("Entity {} has A: {}, B: {} and C: {}", entity, a.x, b.y, c.z);
@@ -61,6 +64,7 @@ fn benchmark(action: impl FnOnce()) -> std::time::Duration
     start.elapsed()
 }
 
+#[allow(dead_code)]
 fn benchmark_main()
 {
     let a = benchmark(|| {
@@ -70,12 +74,12 @@ fn benchmark_main()
             vec[rand::random::<usize>() % 1000000] = i;
         }
 
-        for i in 0..1000
+        for _ in 0..1000
         {
             let _: Option<&i32> = vec.get(rand::random::<usize>() % 1000000);
         }
 
-        for i in 0..1000
+        for _ in 0..1000
         {
             vec.swap_remove(rand::random::<usize>() % vec.len());
         }
@@ -88,15 +92,15 @@ fn benchmark_main()
 
         for i in 0..10000
         {
-            vec.set(rand::random::<usize>() % 1000000, i);
+            vec.set(rand::random::<usize>() % 1000000, i, 0);
         }
             
-        for i in 0..1000
+        for _ in 0..1000
         {
             let _: Option<&i32> = vec.get(rand::random::<usize>() % 1000000);
         }
 
-        for i in 0..1000
+        for _ in 0..1000
         { 
             vec.remove(rand::random::<usize>() % vec.len());
         }
@@ -107,14 +111,78 @@ fn benchmark_main()
     println!("Vec: {:?}, SparseSet: {:?}", a, b);
 }
 
+#[allow(dead_code)]
+fn benchmark_query_main()
+{
+    let ecs = stress_test_world();
+    let storage = ecs.storage();
+
+    // The previous query implementation: every set but the last is collected into a HashMap per call.
+    let a = benchmark(|| {
+        let iter1 = storage.iter_components::<A>().collect::<HashMap<_, _>>();
+        let iter2 = storage.iter_components::<B>().collect::<HashMap<_, _>>();
+        let iter3 = storage.iter_components::<C>();
+
+        iter3.filter_map(|(i3, t3)| {
+            iter1.get(&i3).and_then(|t1| iter2.get(&i3).map(|t2| (i3, *t1, *t2, t3)))
+        }).for_each(|x| { let _ = x; });
+    });
+
+    let b = benchmark(|| {
+        storage.query::<(&A, &B, &C)>().for_each(|x| { let _ = x; });
+    });
+
+    let table_ecs = stress_test_world_with(StorageKind::Table);
+    let table_storage = table_ecs.storage();
+
+    let c = benchmark(|| {
+        table_storage.query::<(&A, &B, &C)>().for_each(|x| { let _ = x; });
+    });
+
+    println!("HashMap join: {:?}, SparseSet intersection: {:?}, archetype tables: {:?}", a, b, c);
+}
+
+#[allow(dead_code)]
+fn benchmark_spawn_batch_main()
+{
+    let a = benchmark(|| {
+        let mut ecs = ecs::ECS::new();
+
+        for _ in 0..100000
+        {
+            let entity = ecs.create_entity();
+            ecs.add_component::<A>(entity);
+            ecs.add_component::<B>(entity);
+            ecs.add_component::<C>(entity);
+        }
+    });
+
+    let b = benchmark(|| {
+        let mut ecs = ecs::ECS::new();
+
+        for _ in 0..100000
+        {
+            ecs.spawn((A::default(), B::default(), C::default()));
+        }
+    });
+
+    let c = benchmark(|| {
+        let mut ecs = ecs::ECS::new();
+
+        ecs.spawn_batch((0..100000).map(|_| (A::default(), B::default(), C::default())));
+    });
+
+    println!("add_component: {:?}, spawn: {:?}, spawn_batch: {:?}", a, b, c);
+}
+
 
 fn main() 
 {   
     let mut vec = TypeErasedVec::new::<u8>();
-    vec.push(1);
+    vec.push(1u8);
     let t = vec.as_slice()[0];
-    vec.push(2);
-    vec.push(3);
+    vec.push(2u8);
+    vec.push(3u8);
     println!("{t}");
 
     /* 
@@ -153,13 +221,21 @@ fn main()
     */
 }
 
-fn stress_test() -> u32
+fn stress_test_world() -> ecs::ECS
+{
+    stress_test_world_with(StorageKind::SparseSet)
+}
+
+fn stress_test_world_with(storage_kind: StorageKind) -> ecs::ECS
 {
     let mut ecs = ecs::ECS::new();
-    ecs.register_system::<MySystem>();
-    let start = std::time::Instant::now();
 
-    for i in 0..100000
+    for component_type_uuid in [ecs.register_component::<A>().id(), ecs.register_component::<B>().id(), ecs.register_component::<C>().id()]
+    {
+        ecs.storage_mut().set_storage_kind(component_type_uuid, storage_kind);
+    }
+
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<A>(entity);
@@ -167,32 +243,32 @@ fn stress_test() -> u32
         ecs.add_component::<C>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<A>(entity);
         ecs.add_component::<B>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<A>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<B>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<C>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<A>(entity);
@@ -200,31 +276,41 @@ fn stress_test() -> u32
         ecs.add_component::<C>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<A>(entity);
         ecs.add_component::<B>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<A>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<B>(entity);
     }
 
-    for i in 0..100000
+    for _ in 0..100000
     {
         let entity = ecs.create_entity();
         ecs.add_component::<C>(entity);
     }
 
+    ecs
+}
+
+#[allow(dead_code)]
+fn stress_test() -> u32
+{
+    let start = std::time::Instant::now();
+    let mut ecs = stress_test_world();
+    ecs.register_system::<MySystem>();
+
     println!("Entities created: {}", ecs.entities_count());
 
     ecs.start();